@@ -0,0 +1,352 @@
+//! Exporter settings from the optional `sio2prom.toml` file, overridden by CLI flags / env vars
+
+use anyhow::{anyhow, Result};
+use clap::{parser::ValueSource, Arg, ArgMatches, Command};
+use serde::Deserialize;
+use std::{
+  collections::{BTreeMap, HashMap},
+  net::SocketAddr,
+  path::Path,
+  str::FromStr,
+};
+
+use crate::sio::{
+  client::BUILTIN_LABELS,
+  definitions::{valid_label_name, valid_metric_name, Source, DEFAULT_PROFILE},
+  filters::{Filter, FILTER_TYPES},
+  metrics::{Options, METRIC_LABELS},
+  rules::LabelRule,
+  utils::valid_json_path,
+};
+
+pub const DEFAULT_CONFIG_FILE: &str = "sio2prom.toml";
+/// Configuration path used when present if none is specified
+pub const DEFAULT_CFG_PATH: &str = "cfg";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+  /// Accept invalid / self-signed gateway certificates
+  pub insecure: Option<bool>,
+  /// PEM encoded CA certificate used to verify the gateways
+  pub ca_cert:  Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listen {
+  pub address: Option<String>,
+  pub port:    Option<u16>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Gateway {
+  pub name:     Option<String>,
+  pub ip:       String,
+  pub auth_usr: Option<String>,
+  pub auth_pwd: Option<String>,
+  pub profile:  Option<String>,
+  pub cfg_path: Option<String>,
+  /// Constant labels of this gateway, merged with the global ones
+  #[serde(default)]
+  pub labels:   BTreeMap<String, String>,
+}
+
+/// Layout of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+  refresh:             Option<u64>,
+  timeout:             Option<u64>,
+  shutdown_timeout:    Option<u64>,
+  profile:             Option<String>,
+  cfg_path:            Option<String>,
+  cfg_mode:            Option<String>,
+  legacy_units:        Option<bool>,
+  state_format:        Option<String>,
+  sds_network_latency: Option<bool>,
+  device_latency:      Option<bool>,
+  namespace:           Option<String>,
+  /// Subsystem per object type (sds, volume...)
+  subsystems:          HashMap<String, String>,
+  /// Include / exclude filters per object type (volume, sdc...)
+  filters:             HashMap<String, Filter>,
+  /// Constant labels added to all the series
+  labels:              BTreeMap<String, String>,
+  /// Instance fields added as labels per object type: label name -> json path (`ipList[0].ip`)
+  fields:              HashMap<String, BTreeMap<String, String>>,
+  /// Labels extracted from other labels with regex capture groups or lookup files
+  label_rules:         Vec<LabelRule>,
+  listen:              Listen,
+  tls:                 Tls,
+  gateways:            Vec<Gateway>,
+}
+
+/// Final settings after merging the configuration file with the CLI / env
+#[derive(Debug, Clone)]
+pub struct Settings {
+  pub refresh:          u64,
+  pub timeout:          u64,
+  pub shutdown_timeout: u64,
+  pub cfg_overlay:      bool,
+  pub listen:           SocketAddr,
+  pub tls:              Tls,
+  pub gateways:         Vec<Gateway>,
+  pub options:          Options,
+}
+
+impl Settings {
+  /// Load the configuration file (if any) and apply the CLI / env overrides, offline (check-config) the gateway and its credentials are optional
+  pub fn new(app: &ArgMatches, offline: bool) -> Result<Settings> {
+    let file = match app.get_one::<String>("config") {
+      Some(f) if user_value(app, "config") => read(f)?,
+      _ if Path::new(DEFAULT_CONFIG_FILE).exists() => read(DEFAULT_CONFIG_FILE)?,
+      _ => File::default(),
+    };
+
+    let profile = arg::<String>(app, "profile")?.or(file.profile).unwrap_or_else(|| default::<String>(app, "profile"));
+    let cfg_path = arg::<String>(app, "cfg_path")?.or(file.cfg_path);
+    let cfg_mode = arg::<String>(app, "cfg_mode")?.or(file.cfg_mode).unwrap_or_else(|| default::<String>(app, "cfg_mode"));
+    let cfg_overlay = match cfg_mode.as_str() {
+      "overlay" => true,
+      "override" => false,
+      m => return Err(anyhow!("The specified cfg_mode is not valid ({m})")),
+    };
+    let auth_usr = arg::<String>(app, "auth_usr")?;
+    let auth_pwd = arg::<String>(app, "auth_pwd")?;
+
+    let mut gateways = match arg::<String>(app, "ip")? {
+      Some(_) if file.gateways.len() > 1 => return Err(anyhow!("--ip can't be combined with several [[gateways]] in the configuration file")),
+      Some(ip) => {
+        vec![Gateway { ip,
+                       ..file.gateways.into_iter().next().unwrap_or_default() }]
+      },
+      None => file.gateways,
+    };
+    if gateways.is_empty() && offline {
+      gateways.push(Gateway::default());
+    }
+    if gateways.is_empty() {
+      return Err(anyhow!("No gateway defined, use --ip or [[gateways]] in the configuration file"));
+    }
+    for gw in gateways.iter_mut() {
+      if auth_usr.is_some() {
+        gw.auth_usr = auth_usr.clone();
+      }
+      if auth_pwd.is_some() {
+        gw.auth_pwd = auth_pwd.clone();
+      }
+      if !offline && (gw.auth_usr.is_none() || gw.auth_pwd.is_none()) {
+        return Err(anyhow!("Missing credentials for the gateway {}", gw.ip));
+      }
+      let gw_profile = gw.profile.get_or_insert_with(|| profile.clone());
+      if gw.cfg_path.is_none() {
+        gw.cfg_path = cfg_path.clone().or_else(|| default_cfg_path(gw_profile));
+      }
+      if let Some(p) = gw.cfg_path.as_ref().filter(|p| !Path::new(p).exists()) {
+        return Err(anyhow!("Config path not found: {p}"));
+      }
+      for (k, v) in file.labels.iter() {
+        gw.labels.entry(k.to_string()).or_insert_with(|| v.to_string());
+      }
+      if let Some(k) = gw.labels.keys().find(|k| !valid_label_name(k)) {
+        return Err(anyhow!("The label {k} of the gateway {} is not a valid label name", gw.ip));
+      }
+      if let Some(k) = gw.labels.keys().find(|k| reserved_label(k)) {
+        return Err(anyhow!("The label {k} of the gateway {} collides with a built-in label", gw.ip));
+      }
+    }
+
+    // Every series of a metric needs the same label names, whatever the gateway
+    let label_names: Vec<String> = gateways.iter().flat_map(|gw| gw.labels.keys().cloned()).collect();
+    for gw in gateways.iter_mut() {
+      for k in label_names.iter() {
+        gw.labels.entry(k.to_string()).or_default();
+      }
+    }
+
+    let fields: HashMap<String, BTreeMap<String, String>> = file.fields.into_iter().map(|(t, f)| (t.to_lowercase(), f)).collect();
+    for (t, f) in fields.iter() {
+      for (k, path) in f.iter() {
+        if !valid_label_name(k) {
+          return Err(anyhow!("The field label {k} ({t}) is not a valid label name"));
+        }
+        if reserved_label(k) || label_names.contains(k) {
+          return Err(anyhow!("The field label {k} ({t}) collides with a built-in or constant label"));
+        }
+        valid_json_path(path).map_err(|e| anyhow!("The field label {k} ({t}): {e}"))?;
+      }
+    }
+
+    let mut known: Vec<String> = fields.values().flat_map(|f| f.keys().cloned()).chain(label_names.iter().cloned()).collect();
+    for rule in file.label_rules.iter() {
+      rule.init()?;
+      for k in rule.label_names() {
+        if !valid_label_name(&k) {
+          return Err(anyhow!("The label {k} of the label rule on {} is not a valid label name", rule.source));
+        }
+        if reserved_label(&k) || known.contains(&k) {
+          return Err(anyhow!("The label {k} of the label rule on {} collides with another label", rule.source));
+        }
+        known.push(k);
+      }
+    }
+
+    let filters: HashMap<String, Filter> = file.filters.into_iter().map(|(t, f)| (t.to_lowercase(), f)).collect();
+    if let Some(t) = filters.keys().find(|t| !FILTER_TYPES.contains(&t.as_str())) {
+      return Err(anyhow!("Unknown filter object type {t}, expected one of: {}", FILTER_TYPES.join(", ")));
+    }
+
+    let address = arg::<String>(app, "listen")?.or(file.listen.address).unwrap_or_else(|| default::<String>(app, "listen"));
+    let port = arg::<u16>(app, "port")?.or(file.listen.port).unwrap_or_else(|| default::<u16>(app, "port"));
+    let listen = SocketAddr::from_str(&format!("{address}:{port}")).map_err(|e| anyhow!("The specified listen address is not valid ({address}:{port}): {e}"))?;
+
+    let state_set = match arg::<String>(app, "state_format")?.or(file.state_format).unwrap_or_else(|| default::<String>(app, "state_format")).as_str() {
+      "numeric" => false,
+      "stateset" => true,
+      f => return Err(anyhow!("The specified state_format is not valid ({f})")),
+    };
+    let namespace = arg::<String>(app, "namespace")?.or(file.namespace).filter(|n| !n.is_empty());
+    if let Some(n) = namespace.as_ref().filter(|n| !valid_metric_name(n)) {
+      return Err(anyhow!("The specified namespace is not valid ({n})"));
+    }
+    let subsystems: HashMap<String, String> = file.subsystems.into_iter().map(|(t, s)| (t.to_lowercase(), s)).collect();
+    if let Some((t, s)) = subsystems.iter().find(|(_, s)| !valid_metric_name(s)) {
+      return Err(anyhow!("The specified subsystem of {t} is not valid ({s})"));
+    }
+
+    let tls = Tls { insecure: arg::<bool>(app, "tls_insecure")?.or(file.tls.insecure).or(Some(default::<bool>(app, "tls_insecure"))),
+                    ca_cert:  arg::<String>(app, "tls_ca_cert")?.or(file.tls.ca_cert) };
+
+    Ok(Settings { refresh: arg::<u64>(app, "refresh")?.or(file.refresh).unwrap_or_else(|| default::<u64>(app, "refresh")),
+                  timeout: arg::<u64>(app, "timeout")?.or(file.timeout).unwrap_or_else(|| default::<u64>(app, "timeout")),
+                  shutdown_timeout: arg::<u64>(app, "shutdown_timeout")?.or(file.shutdown_timeout).unwrap_or_else(|| default::<u64>(app, "shutdown_timeout")),
+                  cfg_overlay,
+                  listen,
+                  tls,
+                  gateways,
+                  options: Options { legacy_units: arg::<bool>(app, "legacy_units")?.or(file.legacy_units).unwrap_or_else(|| default::<bool>(app, "legacy_units")),
+                                     namespace,
+                                     subsystems,
+                                     filters,
+                                     labels: BTreeMap::new(),
+                                     fields,
+                                     rules: file.label_rules,
+                                     state_set,
+                                     sds_network_latency: arg::<bool>(app, "sds_network_latency")?.or(file.sds_network_latency).unwrap_or_else(|| default::<bool>(app, "sds_network_latency")),
+                                     device_latency: arg::<bool>(app, "device_latency")?.or(file.device_latency).unwrap_or_else(|| default::<bool>(app, "device_latency")) } })
+  }
+
+  /// Metric options of a gateway
+  pub fn options(&self, gw: &Gateway) -> Options {
+    Options { labels: gw.labels.clone(),
+              ..self.options.clone() }
+  }
+
+  /// Source of the metric definitions of a gateway
+  pub fn source(&self, gw: &Gateway) -> Source {
+    Source { profile:  gw.profile.clone().unwrap_or_default(),
+             cfg_path: gw.cfg_path.clone(),
+             overlay:  self.cfg_overlay, }
+  }
+}
+
+/// Label generated by the collector, on the object series or on some of its own metrics
+fn reserved_label(name: &str) -> bool { BUILTIN_LABELS.contains(&name) || METRIC_LABELS.contains(&name) }
+
+/// `cfg` of the working directory if present, only applied on the default profile: it holds the default profile files in the repository and the documented Docker setup
+fn default_cfg_path(profile: &str) -> Option<String> { (profile == DEFAULT_PROFILE && Path::new(DEFAULT_CFG_PATH).exists()).then(|| DEFAULT_CFG_PATH.to_string()) }
+
+/// Command line arguments and their env variables
+pub fn args() -> Command {
+  Command::new("").version(env!("CARGO_PKG_VERSION"))
+                  .author(env!("CARGO_PKG_AUTHORS"))
+                  .about(env!("CARGO_PKG_DESCRIPTION"))
+                  .arg(Arg::new("config").long("config").env("SIO2PROM_CONFIG_FILE").required(false).num_args(1).default_value(DEFAULT_CONFIG_FILE).help("Configuration file (TOML), overridden by the other parameters"))
+                  .arg(Arg::new("refresh").short('r').long("refresh").env("SIO2PROM_REFRESH").required(false).num_args(1).default_value("60").help("Refresh interval in seconds"))
+                  .arg(Arg::new("timeout").long("timeout").env("SIO2PROM_TIMEOUT").required(false).num_args(1).default_value("15").help("Gateway request timeout in seconds"))
+                  .arg(Arg::new("profile").long("profile").env("SIO2PROM_PROFILE").required(false).num_args(1).default_value(DEFAULT_PROFILE).help("Embedded metric configuration profile (default, 3.6)"))
+                  .arg(Arg::new("cfg_path").short('c').long("cfg_path").env("SIO2PROM_CFG_PATH").required(false).num_args(1).help("Configuration path, its files are applied on top of the profile (default: cfg if present, with the default profile only)"))
+                  .arg(Arg::new("cfg_mode").long("cfg_mode").env("SIO2PROM_CFG_MODE").required(false).num_args(1).default_value("overlay").value_parser(["overlay", "override"]).help("Merge the configuration path files with the profile (overlay) or replace it (override)"))
+                  .arg(Arg::new("legacy_units").long("legacy_units").env("SIO2PROM_LEGACY_UNITS").required(false).num_args(1).default_value("false").value_parser(["true", "false"]).help("Keep the legacy metric units (_kb, _us...) instead of the base units (_bytes, _seconds)"))
+                  .arg(Arg::new("state_format").long("state_format").env("SIO2PROM_STATE_FORMAT").required(false).num_args(1).default_value("numeric").value_parser(["numeric", "stateset"]).help("Expose the states as numbers or as StateSets (one series per state with a state label)"))
+                  .arg(Arg::new("sds_network_latency").long("sds_network_latency").env("SIO2PROM_SDS_NETWORK_LATENCY").required(false).num_args(1).default_value("false").value_parser(["true", "false"]).help("Collect the SDS to SDS network latency meters (one request per SDS)"))
+                  .arg(Arg::new("device_latency").long("device_latency").env("SIO2PROM_DEVICE_LATENCY").required(false).num_args(1).default_value("false").value_parser(["true", "false"]).help("Collect the device latency meters (one request per SDS)"))
+                  .arg(Arg::new("namespace").long("namespace").env("SIO2PROM_NAMESPACE").required(false).num_args(1).help("Prefix added to all the exported PowerFlex metric names (e.g. powerflex)"))
+                  .arg(Arg::new("listen").long("listen").env("SIO2PROM_LISTEN").required(false).num_args(1).default_value("0.0.0.0").help("Metric listening address"))
+                  .arg(Arg::new("port").long("port").env("SIO2PROM_PORT").required(false).num_args(1).default_value("8080").help("Metric listening port"))
+                  .arg(Arg::new("shutdown_timeout").long("shutdown_timeout").env("SIO2PROM_SHUTDOWN_TIMEOUT").required(false).num_args(1).default_value("10").help("Seconds to wait for an in-flight collection on shutdown"))
+                  .arg(Arg::new("tls_insecure").long("tls_insecure").env("SIO2PROM_TLS_INSECURE").required(false).num_args(1).default_value("true").value_parser(["true", "false"]).help("Accept invalid gateway certificates"))
+                  .arg(Arg::new("tls_ca_cert").long("tls_ca_cert").env("SIO2PROM_TLS_CA_CERT").required(false).num_args(1).help("CA certificate (PEM) used to verify the gateways"))
+                  .arg(Arg::new("ip").short('i').long("ip").env("SIO2PROM_IP").required(false).num_args(1).help("Gateway IP"))
+                  .arg(Arg::new("auth_usr").short('u').long("auth_usr").env("SIO2PROM_AUTH_USR").required(false).num_args(1).help("Gateway Username"))
+                  .arg(Arg::new("auth_pwd").short('p').long("auth_pwd").env("SIO2PROM_AUTH_PWD").required(false).num_args(1).help("Gateway Password"))
+                  .subcommand(Command::new("check-config").about("Validate the metric configuration files and exit").arg(Arg::new("paths").num_args(0..).help("Configuration paths to check on top of the profile (default: --cfg_path)")))
+                  .arg(Arg::new("v").short('v').action(clap::ArgAction::Count).required(false).help("Log verbosity (-v, -vv, -vvv...)"))
+}
+
+/// Read and parse the configuration file
+fn read(file: &str) -> Result<File> {
+  let content = std::fs::read_to_string(file).map_err(|e| anyhow!("Failed to open file: {}, {:?}", file, e.kind()))?;
+  toml::from_str::<File>(&content).map_err(|e| anyhow!("Can't deserialize config file {file}: {e}"))
+}
+
+/// Was the argument given on the command line or env (not the default value)
+fn user_value(app: &ArgMatches, id: &str) -> bool { matches!(app.value_source(id), Some(ValueSource::CommandLine) | Some(ValueSource::EnvVariable)) }
+
+/// Argument value only if given on the command line or env
+fn arg<T: FromStr>(app: &ArgMatches, id: &str) -> Result<Option<T>> {
+  if !user_value(app, id) {
+    return Ok(None);
+  }
+  let v = app.get_one::<String>(id).unwrap();
+  v.parse::<T>().map(Some).map_err(|_| anyhow!("The specified {id} is not valid ({v})"))
+}
+
+/// Argument default value as defined in clap
+fn default<T: FromStr>(app: &ArgMatches, id: &str) -> T {
+  app.get_one::<String>(id).and_then(|v| v.parse::<T>().ok()).unwrap_or_else(|| panic!("Invalid default value for {id}"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::sio::definitions::Definitions;
+
+  /// Settings of a single gateway given on the command line, run from the crate root (where `cfg` holds the default profile files)
+  fn settings(extra: &[&str]) -> Settings {
+    let app = args().try_get_matches_from([&["sio2prom", "-i", "gw1", "-u", "usr", "-p", "pwd"], extra].concat()).unwrap();
+    Settings::new(&app, false).unwrap()
+  }
+
+  /// Query properties of the gateway definitions
+  fn properties(s: &Settings) -> Vec<String> {
+    let defs = Definitions::load(&s.source(&s.gateways[0])).unwrap();
+    defs.selection().into_iter().flat_map(|(_, p)| p).map(|p| p.to_string()).collect()
+  }
+
+  #[test]
+  fn auto_cfg_path_default_profile() {
+    let s = settings(&[]);
+    assert_eq!(s.gateways[0].cfg_path.as_deref(), Some(DEFAULT_CFG_PATH));
+    assert!(properties(&s).contains(&"fixedReadErrorCount".to_string()));
+  }
+
+  #[test]
+  fn auto_cfg_path_other_profile() {
+    let s = settings(&["--profile", "3.6"]);
+    assert_eq!(s.gateways[0].cfg_path, None);
+    let props = properties(&s);
+    assert!(props.contains(&"backgroundScanFixedReadErrorCount".to_string()));
+    assert!(!props.contains(&"fixedReadErrorCount".to_string()));
+  }
+
+  #[test]
+  fn explicit_cfg_path_other_profile() {
+    let s = settings(&["--profile", "3.6", "--cfg_path", "cfg/3.6"]);
+    assert_eq!(s.gateways[0].cfg_path.as_deref(), Some("cfg/3.6"));
+    let s = settings(&["--profile", "3.6", "--cfg_path", "cfg"]);
+    assert!(properties(&s).contains(&"fixedReadErrorCount".to_string()));
+  }
+}
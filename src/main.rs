@@ -1,21 +1,28 @@
+mod config;
 mod sio;
 use color_eyre::eyre::Result;
-use std::{collections::HashMap, io::Write, path::Path, process::exit, time::Duration};
-use tokio::sync::Mutex;
+use std::{
+  collections::HashMap,
+  io::Write,
+  process::exit,
+  sync::{Arc, RwLock},
+  time::Duration,
+};
+use tokio::sync::{watch, Mutex};
 
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 
 extern crate clap;
-use clap::{Arg, ArgMatches, Command};
+use clap::ArgMatches;
 
 #[macro_use]
 extern crate lazy_static;
 
 #[macro_use]
 extern crate prometheus;
-use prometheus::{GaugeVec, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry};
+use prometheus::{GaugeVec, Histogram, HistogramOpts, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry};
 
 use warp::{Filter, Rejection, Reply};
 
@@ -23,9 +30,11 @@ lazy_static! {
   static ref REGISTRY: Registry = Registry::new();
   static ref HTTP_BODY_GAUGE: IntGauge = IntGauge::new("sio2prom_http_response_size_bytes", "The HTTP response sizes in bytes").expect("metric can be created");
   static ref HTTP_REQ_HISTOGRAM: Histogram = Histogram::with_opts(HistogramOpts::new("sio2prom_http_request_duration_seconds", "The HTTP request latencies in seconds")).expect("metric can be created");
+  static ref CONFIG_RELOAD_GAUGE: IntGaugeVec = IntGaugeVec::new(Opts::new("sio2prom_config_reload_success", "Whether the last metric configuration reload succeeded"), &["source"]).expect("metric can be created");
   static ref UPDATE_HISTOGRAM: Histogram = Histogram::with_opts(HistogramOpts::new("sio2prom_update_duration_seconds", "The time in seconds it took to collect the stats")).expect("metric can be created");
   static ref METRIC_COUNTERS: Mutex<HashMap<String, IntCounterVec>> = Mutex::new(HashMap::new());
   static ref METRIC_GAUGES: Mutex<HashMap<String, GaugeVec>> = Mutex::new(HashMap::new());
+  static ref METRIC_UNITS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
   static ref TOKIO_INSTRUMENTED_COUNT: IntGauge = IntGauge::new("sio2prom_tokio_instrumented_count", "The number of tasks instrumented").expect("metric can be created");
   static ref TOKIO_DROPPED_COUNT: IntGauge = IntGauge::new("sio2prom_tokio_dropped_count", "The number of tasks dropped").expect("metric can be created");
   static ref TOKIO_FIRST_POLL_COUNT: IntGauge = IntGauge::new("sio2prom_tokio_first_poll_count", "The number of tasks polled for the first time").expect("metric can be created");
@@ -41,21 +50,29 @@ lazy_static! {
   static ref TOKIO_TOTAL_SLOW_POLL_DURATION: IntGauge = IntGauge::new("sio2prom_tokio_total_slow_poll_duration_ms", "The total duration of slow polls").expect("metric can be created");
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error+Send+Sync>> {
+/// Generic env variable names accepted before the SIO2PROM_ prefix
+const LEGACY_ENV: [&str; 6] = ["REFRESH", "CFG_PATH", "PORT", "IP", "AUTH_USR", "AUTH_PWD"];
+
+/// Copy the legacy env variables to their prefixed names (unless set) and return the ones in use, called before the runtime starts
+fn legacy_env() -> Vec<&'static str> {
+  let mut used = Vec::new();
+  for e in LEGACY_ENV {
+    if let Ok(v) = std::env::var(e) {
+      used.push(e);
+      if std::env::var(format!("SIO2PROM_{e}")).is_err() {
+        std::env::set_var(format!("SIO2PROM_{e}"), v);
+      }
+    }
+  }
+  used
+}
+
+/// The arguments and the logger are set up before the runtime starts its threads, as they change the environment
+fn main() -> Result<(), Box<dyn std::error::Error+Send+Sync>> {
   color_eyre::install()?;
 
-  let app = Command::new("").version(env!("CARGO_PKG_VERSION"))
-                            .author(env!("CARGO_PKG_AUTHORS"))
-                            .about(env!("CARGO_PKG_DESCRIPTION"))
-                            .arg(Arg::new("refresh").short('r').long("refresh").env("REFRESH").required(false).num_args(1).default_value("60").help("Refresh interval in seconds"))
-                            .arg(Arg::new("cfg_path").short('c').long("cfg_path").env("CFG_PATH").required(false).num_args(1).default_value("cfg").help("Configuration path"))
-                            .arg(Arg::new("port").long("port").env("PORT").required(false).num_args(1).default_value("8080").help("Metric listening port"))
-                            .arg(Arg::new("ip").short('i').long("ip").env("IP").required(true).num_args(1).help("Gateway IP"))
-                            .arg(Arg::new("auth_usr").short('u').long("auth_usr").env("AUTH_USR").required(true).num_args(1).help("Gateway Username"))
-                            .arg(Arg::new("auth_pwd").short('p').long("auth_pwd").env("AUTH_PWD").requires("auth_usr").required(true).num_args(1).help("Gateway Password"))
-                            .arg(Arg::new("v").short('v').action(clap::ArgAction::Count).required(false).help("Log verbosity (-v, -vv, -vvv...)"))
-                            .get_matches();
+  let legacy_env = legacy_env();
+  let app = config::args().get_matches();
 
   match app.get_one::<u8>("v").unwrap() {
     0 => std::env::set_var("RUST_LOG", "error"),
@@ -67,26 +84,99 @@ async fn main() -> Result<(), Box<dyn std::error::Error+Send+Sync>> {
   }
 
   env_logger::Builder::from_default_env().format(|buf, record| writeln!(buf, "{} {} {}:{} [{}] - {}", chrono::Local::now().format("%Y-%m-%dT%H:%M:%S"), record.module_path().unwrap_or("unknown"), record.file().unwrap_or("unknown"), record.line().unwrap_or(0), record.level(), record.args())).init();
+  for e in legacy_env {
+    warn!("The env variable {e} is deprecated, use SIO2PROM_{e}");
+  }
 
-  if !Path::new(&app.get_one::<String>("cfg_path").unwrap()).exists() {
-    error!("Config path not found: {}", app.get_one::<String>("cfg_path").unwrap());
-    exit(1);
+  tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(run(app))
+}
+
+async fn run(app: ArgMatches) -> Result<(), Box<dyn std::error::Error+Send+Sync>> {
+
+  if let Some(cmd) = app.subcommand_matches("check-config") {
+    let settings = match config::Settings::new(&app, true) {
+      Ok(s) => s,
+      Err(e) => {
+        println!("ERROR {e}");
+        exit(1);
+      },
+    };
+    // The sources of the gateways (or the given paths on top of their profile) with the options they are used with
+    let mut checks: Vec<(sio::definitions::Source, sio::metrics::Options)> = Vec::new();
+    for gw in &settings.gateways {
+      let source = settings.source(gw);
+      let sources: Vec<sio::definitions::Source> = match cmd.get_many::<String>("paths") {
+        Some(p) => {
+          p.map(|p| sio::definitions::Source { cfg_path: Some(p.to_string()),
+                                               ..source.clone() })
+           .collect()
+        },
+        None => vec![source],
+      };
+      for s in sources {
+        if !checks.iter().any(|(c, _)| *c == s) {
+          checks.push((s, settings.options(gw)));
+        }
+      }
+    }
+    exit(check_config(&checks));
   }
 
-  let port = &app.get_one::<String>("port").and_then(|s| s.parse::<u16>().ok());
-  if port.is_none() {
-    error!("The specified port is not valid ({})", &app.get_one::<String>("port").unwrap());
-    exit(1);
+  let settings = match config::Settings::new(&app, false) {
+    Ok(s) => s,
+    Err(e) => {
+      error!("Invalid configuration: {}", e);
+      exit(1);
+    },
+  };
+
+  // One set of metric definitions per source, shared by the gateways using it
+  let mut defs: HashMap<sio::definitions::Source, sio::definitions::SharedDefinitions> = HashMap::new();
+  let mut reload_opts: HashMap<sio::definitions::Source, Vec<sio::metrics::Options>> = HashMap::new();
+  for gw in &settings.gateways {
+    let source = settings.source(gw);
+    if !defs.contains_key(&source) {
+      match sio::definitions::Definitions::load(&source) {
+        Ok(d) => {
+          info!("Loaded metric configuration: {}", source);
+          CONFIG_RELOAD_GAUGE.with_label_values(&[&source.to_string()]).set(1);
+          defs.insert(source.clone(), Arc::new(RwLock::new(Arc::new(d))));
+        },
+        Err(e) => {
+          error!("Invalid metric configuration: {}", e);
+          exit(1);
+        },
+      };
+    }
+
+    // The info labels depend on the labels of each gateway sharing the definitions
+    let problems = defs[&source].read().unwrap().label_problems(&settings.options(gw));
+    if !problems.is_empty() {
+      error!("Invalid metric configuration for the gateway {} ({}): {}", gw.name.as_deref().unwrap_or_default(), gw.ip, problems.join(", "));
+      exit(1);
+    }
+    reload_opts.entry(source).or_default().push(settings.options(gw));
   }
 
   register_metrics();
 
+  let (shutdown_tx, shutdown_rx) = watch::channel(false);
+  tokio::spawn(async move {
+    shutdown_signal().await;
+    let _ = shutdown_tx.send(true);
+  });
+
   let monitor = tokio_metrics::TaskMonitor::new();
   let monitor_data = monitor.clone();
+  let data_shutdown = shutdown_rx.clone();
+  let data_defs = defs.clone();
+  let data_settings = settings.clone();
   let data_handle = tokio::task::spawn(async move {
-    monitor_data.instrument(data_collector(app)).await;
+    monitor_data.instrument(data_collector(data_settings, data_defs, data_shutdown)).await;
   });
 
+  let reload_handles = defs.into_iter().map(|(source, d)| tokio::spawn(config_reloader(reload_opts.remove(&source).unwrap_or_default(), source, d, shutdown_rx.clone()))).collect::<Vec<_>>();
+
   let monitor_tokio = monitor.clone();
   let tokio_handle = tokio::spawn(async move {
     for metrics in monitor_tokio.intervals() {
@@ -108,15 +198,115 @@ async fn main() -> Result<(), Box<dyn std::error::Error+Send+Sync>> {
     }
   });
 
-  let metrics_route = warp::path!("metrics").and_then(metrics_handler);
-  let warp_handle = warp::serve(metrics_route).run(([0, 0, 0, 0], port.unwrap()));
-
-  info!("Started on port http://127.0.0.1:{}/metrics", port.unwrap());
-  let _ = tokio::join!(tokio_handle, data_handle, warp_handle);
+  let metrics_route = warp::path!("metrics").and(warp::header::optional::<String>("accept")).and_then(metrics_handler);
+  let mut warp_shutdown = shutdown_rx.clone();
+  let (_, warp_server) = warp::serve(metrics_route).bind_with_graceful_shutdown(settings.listen, async move {
+                                                      let _ = warp_shutdown.changed().await;
+                                                    });
+  let warp_handle = tokio::spawn(warp_server);
+
+  info!("Started on http://{}/metrics", settings.listen);
+  let _ = tokio::join!(data_handle, warp_handle);
+  for h in reload_handles {
+    let _ = h.await;
+  }
+  tokio_handle.abort();
+  info!("Shutdown complete");
   Ok(())
 }
 
+/// Offline validation of the metric configuration files, returns the exit code
+fn check_config(checks: &[(sio::definitions::Source, sio::metrics::Options)]) -> i32 {
+  let mut failed = false;
+
+  for (source, opts) in checks {
+    match sio::definitions::Definitions::load(source) {
+      Err(e) => {
+        failed = true;
+        println!("{source}: ERROR {e}");
+      },
+      Ok(d) => {
+        let problems = d.check(opts);
+        for p in &problems {
+          println!("{source}: ERROR {p}");
+        }
+        if problems.is_empty() {
+          println!("{source}: OK");
+        } else {
+          failed = true;
+        }
+      },
+    }
+  }
+
+  i32::from(failed)
+}
+
+/// Wait for SIGINT or SIGTERM
+async fn shutdown_signal() {
+  let ctrl_c = async {
+    tokio::signal::ctrl_c().await.expect("failed to install the SIGINT handler");
+  };
+
+  #[cfg(unix)]
+  let terminate = async {
+    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("failed to install the SIGTERM handler").recv().await;
+  };
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+
+  tokio::select! {
+    _ = ctrl_c => info!("Received SIGINT, shutting down"),
+    _ = terminate => info!("Received SIGTERM, shutting down"),
+  }
+}
+
+/// Reload the metric configuration on SIGHUP or when the files change, keeping the last good one on error or when its info labels collide with the labels of a gateway (`opts`)
+async fn config_reloader(opts: Vec<sio::metrics::Options>, source: sio::definitions::Source, defs: sio::definitions::SharedDefinitions, mut shutdown: watch::Receiver<bool>) {
+  #[cfg(unix)]
+  let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).expect("failed to install the SIGHUP handler");
+  let mut check_interval = tokio::time::interval(Duration::from_secs(5));
+  let mut modified = sio::definitions::modified(&source);
+
+  loop {
+    #[cfg(unix)]
+    let hangup_recv = hangup.recv();
+    #[cfg(not(unix))]
+    let hangup_recv = std::future::pending::<Option<()>>();
+
+    tokio::select! {
+      _ = hangup_recv => info!("Received SIGHUP, reloading the metric configuration"),
+      _ = check_interval.tick() => {
+        let current = sio::definitions::modified(&source);
+        if current == modified {
+          continue;
+        }
+        info!("Metric configuration changed on disk, reloading");
+      },
+      _ = shutdown.changed() => break,
+    }
+    modified = sio::definitions::modified(&source);
+
+    let reload = sio::definitions::Definitions::load(&source).and_then(|d| match opts.iter().flat_map(|o| d.label_problems(o)).collect::<Vec<_>>() {
+                                                                 p if p.is_empty() => Ok(d),
+                                                                 p => Err(anyhow::anyhow!(p.join(", "))),
+                                                               });
+    match reload {
+      Ok(d) => {
+        *defs.write().unwrap() = Arc::new(d);
+        CONFIG_RELOAD_GAUGE.with_label_values(&[&source.to_string()]).set(1);
+        info!("Metric configuration reloaded from {}", source);
+      },
+      Err(e) => {
+        CONFIG_RELOAD_GAUGE.with_label_values(&[&source.to_string()]).set(0);
+        error!("Rejected metric configuration reload, keeping the last good one: {}", e);
+      },
+    }
+  }
+}
+
 fn register_metrics() {
+  REGISTRY.register(Box::new(CONFIG_RELOAD_GAUGE.clone())).expect("collector can be registered");
   REGISTRY.register(Box::new(HTTP_BODY_GAUGE.clone())).expect("collector can be registered");
   REGISTRY.register(Box::new(HTTP_REQ_HISTOGRAM.clone())).expect("collector can be registered");
   REGISTRY.register(Box::new(UPDATE_HISTOGRAM.clone())).expect("collector can be registered");
@@ -135,30 +325,87 @@ fn register_metrics() {
   REGISTRY.register(Box::new(TOKIO_TOTAL_SLOW_POLL_DURATION.clone())).expect("collector can be registered");
 }
 
-async fn data_collector(app: ArgMatches) {
-  let refresh = app.get_one::<String>("refresh").unwrap().parse::<u64>().unwrap_or(60);
-  let mut collect_interval = tokio::time::interval(Duration::from_secs(refresh));
-
-  let mut sio = sio::client::ClientInfo::new(app.get_one::<String>("cfg_path").map(|s| s.as_str()), app.get_one::<String>("ip").map(|s| s.as_str()), app.get_one::<String>("auth_usr").map(|s| s.as_str()), app.get_one::<String>("auth_pwd").map(|s| s.as_str()));
-  if sio.version().await.is_err() {
-    exit(1);
+async fn data_collector(settings: config::Settings, defs: HashMap<sio::definitions::Source, sio::definitions::SharedDefinitions>, mut shutdown: watch::Receiver<bool>) {
+  let mut collect_interval = tokio::time::interval(Duration::from_secs(settings.refresh));
+
+  let mut clients: Vec<sio::client::ClientInfo> = Vec::new();
+  for gw in &settings.gateways {
+    let gw_defs = defs[&settings.source(gw)].clone();
+    let mut sio = match sio::client::ClientInfo::new(gw_defs, Some(gw.ip.as_str()), gw.auth_usr.as_deref(), gw.auth_pwd.as_deref(), Duration::from_secs(settings.timeout), &settings.tls, settings.options(gw)) {
+      Ok(c) => c,
+      Err(e) => {
+        error!("Invalid gateway {} ({}): {}", gw.name.as_deref().unwrap_or_default(), gw.ip, e);
+        exit(1);
+      },
+    };
+    if sio.version().await.is_err() {
+      error!("Failed to connect to the gateway {} ({})", gw.name.as_deref().unwrap_or_default(), gw.ip);
+      exit(1);
+    }
+    clients.push(sio);
   }
 
   loop {
-    let metrics = sio.metrics().await;
+    let metrics = {
+      let cycle = collect(&mut clients);
+      tokio::pin!(cycle);
+
+      tokio::select! {
+        m = &mut cycle => m,
+        _ = shutdown.changed() => {
+          info!("Waiting up to {}s for the current collection to finish", settings.shutdown_timeout);
+          match tokio::time::timeout(Duration::from_secs(settings.shutdown_timeout), &mut cycle).await {
+            Ok(m) => m,
+            Err(_) => {
+              warn!("The current collection did not finish in time, cancelling it");
+              None
+            },
+          }
+        },
+      }
+    };
+
     if let Some(m) = metrics {
       let timer = UPDATE_HISTOGRAM.start_timer();
       unreg_metrics(&m).await;
-      load_metrics(&m).await;
-      update_metrics(&m).await;
+      load_metrics(&m, &settings.options).await;
+      update_metrics(&m, &settings.options).await;
       timer.observe_duration();
     }
 
-    collect_interval.tick().await;
+    if *shutdown.borrow() {
+      break;
+    }
+
+    tokio::select! {
+      _ = collect_interval.tick() => {},
+      _ = shutdown.changed() => break,
+    }
+  }
+
+  for sio in clients.iter_mut() {
+    sio.logout().await;
+  }
+}
+
+/// Collect the metrics of all the gateways
+async fn collect(clients: &mut [sio::client::ClientInfo<'_>]) -> Option<Vec<sio::metrics::Metric>> {
+  let mut metric_list: Vec<sio::metrics::Metric> = Vec::new();
+  for sio in clients.iter_mut() {
+    if let Some(mut m) = sio.metrics().await {
+      metric_list.append(&mut m);
+    }
+  }
+
+  if metric_list.is_empty() {
+    None
+  } else {
+    Some(metric_list)
   }
 }
 
-async fn metrics_handler() -> Result<impl Reply, Rejection> {
+/// Text exposition format, or OpenMetrics (with the `# UNIT` lines) when the scraper accepts it
+async fn metrics_handler(accept: Option<String>) -> Result<impl Reply, Rejection> {
   let timer = HTTP_REQ_HISTOGRAM.start_timer();
   use prometheus::Encoder;
   let encoder = prometheus::TextEncoder::new();
@@ -190,58 +437,88 @@ async fn metrics_handler() -> Result<impl Reply, Rejection> {
   buffer.clear();
 
   res.push_str(&res_custom);
+  let content_type = if accept.is_some_and(|a| a.contains("application/openmetrics-text")) {
+    res = openmetrics(&res, &*METRIC_UNITS.lock().await);
+    "application/openmetrics-text; version=1.0.0; charset=utf-8"
+  } else {
+    "text/plain; version=0.0.4; charset=utf-8"
+  };
   timer.observe_duration();
   HTTP_BODY_GAUGE.set(res.len() as i64);
-  Ok(res)
+  Ok(warp::reply::with_header(res, "content-type", content_type))
+}
+
+/// Convert the text exposition format to OpenMetrics: `# UNIT` line after the `# TYPE` line of the metrics having one, counter families without the `_total` suffix of their samples and final `# EOF`
+fn openmetrics(text: &str, units: &HashMap<String, String>) -> String {
+  let counters: Vec<&str> = text.lines().filter_map(|l| l.strip_prefix("# TYPE ")?.strip_suffix(" counter")).collect();
+  let family = |name: &str| name.strip_suffix("_total").unwrap_or(name).to_string();
+  let mut res = String::with_capacity(text.len());
+
+  for line in text.lines() {
+    if let Some((kind, rest)) = line.strip_prefix("# HELP ").map(|r| ("HELP", r)).or_else(|| line.strip_prefix("# TYPE ").map(|r| ("TYPE", r))) {
+      let (name, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+      let fname = if counters.contains(&name) { family(name) } else { name.to_string() };
+      res.push_str(&format!("# {kind} {fname} {tail}\n"));
+      if let Some(unit) = units.get(name).filter(|_| kind == "TYPE") {
+        res.push_str(&format!("# UNIT {fname} {unit}\n"));
+      }
+      continue;
+    }
+    let name = line.split(['{', ' ']).next().unwrap_or_default();
+    if !line.starts_with('#') && counters.contains(&name) {
+      res.push_str(&format!("{}_total{}\n", family(name), &line[name.len()..]));
+    } else {
+      res.push_str(line);
+      res.push('\n');
+    }
+  }
+  res.push_str("# EOF\n");
+  res
 }
 
+/// Reset all the registered series before the update, the series of the objects or alerts that disappeared are dropped
 async fn unreg_metrics(metrics: &[sio::metrics::Metric]) {
   let counters = METRIC_COUNTERS.lock().await;
   let gauges = METRIC_GAUGES.lock().await;
 
   info!("UnRegistering series: {:?}", metrics.len());
-  for m in metrics {
-    trace!("UnRegistering metric: {} ({})", m.name, m.mtype);
-    if m.mtype.to_lowercase() == "counter" {
-      let c = counters.get(&m.name);
-      let c = match c {
-        None => continue,
-        Some(c) => c,
-      };
-      c.reset();
-    } else if m.mtype.to_lowercase() == "gauge" {
-      let g = gauges.get(&m.name);
-      let g = match g {
-        None => continue,
-        Some(g) => g,
-      };
-      g.reset();
-    }
+  for (name, c) in counters.iter() {
+    trace!("UnRegistering metric: {} (counter)", name);
+    c.reset();
+  }
+  for (name, g) in gauges.iter() {
+    trace!("UnRegistering metric: {} (gauge)", name);
+    g.reset();
   }
 }
 
-async fn load_metrics(metrics: &[sio::metrics::Metric]) {
+async fn load_metrics(metrics: &[sio::metrics::Metric], options: &sio::metrics::Options) {
   let mut counters = METRIC_COUNTERS.lock().await;
   let mut gauges = METRIC_GAUGES.lock().await;
+  let mut units = METRIC_UNITS.lock().await;
 
   info!("Loaded series: {:?}", metrics.len());
   for m in metrics {
     let labels: Vec<&str> = m.labels.iter().map(|v| *v.0).collect::<Vec<_>>();
-    let opts = Opts::new(m.name.to_string(), m.help.to_string());
+    let opts = Opts::new(m.name.to_string(), m.help.to_string()).namespace(options.namespace.as_deref().unwrap_or_default()).subsystem(options.subsystem(&m.otype).unwrap_or_default());
+    let fq_name = opts.fq_name();
+    if let Some(u) = &m.unit {
+      units.entry(fq_name.clone()).or_insert_with(|| u.to_string());
+    }
     trace!("Registering metric: {} {:?} ({})", m.name, labels, m.mtype);
 
     if m.mtype.to_lowercase() == "counter" {
       match register_int_counter_vec!(opts, &labels) {
         Err(_) => continue,
         Ok(o) => {
-          counters.insert(m.name.clone().to_string(), o);
+          counters.insert(fq_name.clone(), o);
         },
       };
     } else if m.mtype.to_lowercase() == "gauge" {
       match register_gauge_vec!(opts, &labels) {
         Err(_) => continue,
         Ok(o) => {
-          gauges.insert(m.name.clone().to_string(), o);
+          gauges.insert(fq_name.clone(), o);
         },
       };
     } else {
@@ -252,7 +529,7 @@ async fn load_metrics(metrics: &[sio::metrics::Metric]) {
   info!("Loaded Gauges: {:?}", gauges.keys().count());
 }
 
-async fn update_metrics(metrics: &[sio::metrics::Metric]) {
+async fn update_metrics(metrics: &[sio::metrics::Metric], options: &sio::metrics::Options) {
   info!("Update metrics");
 
   let counters = METRIC_COUNTERS.lock().await;
@@ -263,9 +540,10 @@ async fn update_metrics(metrics: &[sio::metrics::Metric]) {
     for (k, v) in &m.labels {
       labels.insert(k, v);
     }
+    let fq_name = options.fq_name(&m.otype, &m.name);
 
     if m.mtype.to_lowercase() == "counter" {
-      let c = counters.get(&m.name);
+      let c = counters.get(&fq_name);
       let c = match c {
         None => {
           error!("The metric {} ({}) was not found as registered", m.name, m.mtype);
@@ -285,7 +563,7 @@ async fn update_metrics(metrics: &[sio::metrics::Metric]) {
 
       metric.inc_by(m.value as u64);
     } else if m.mtype.to_lowercase() == "gauge" {
-      let g = gauges.get(&m.name);
+      let g = gauges.get(&fq_name);
       let g = match g {
         None => {
           error!("The metric {} ({}) was not found as registered", m.name, m.mtype);
@@ -311,3 +589,16 @@ async fn update_metrics(metrics: &[sio::metrics::Metric]) {
   info!("Updated Counters: {:?}", counters.keys().count());
   info!("Updated Gauges: {:?}", gauges.keys().count());
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn openmetrics_units_and_counters() {
+    let text = "# HELP sio_sds_read_bytes Read bandwidth\n# TYPE sio_sds_read_bytes gauge\nsio_sds_read_bytes{name=\"sds1\"} 1024\n# HELP sio_requests_total Requests\n# TYPE sio_requests_total counter\nsio_requests_total 3\n# HELP sio_errors Errors\n# TYPE sio_errors counter\nsio_errors{code=\"1\"} 2\n";
+    let units = HashMap::from([("sio_sds_read_bytes".to_string(), "bytes".to_string())]);
+    assert_eq!(openmetrics(text, &units),
+               "# HELP sio_sds_read_bytes Read bandwidth\n# TYPE sio_sds_read_bytes gauge\n# UNIT sio_sds_read_bytes bytes\nsio_sds_read_bytes{name=\"sds1\"} 1024\n# HELP sio_requests Requests\n# TYPE sio_requests counter\nsio_requests_total 3\n# HELP sio_errors Errors\n# TYPE sio_errors counter\nsio_errors_total{code=\"1\"} 2\n# EOF\n");
+  }
+}
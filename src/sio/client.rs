@@ -1,30 +1,63 @@
 use anyhow::{anyhow, Result};
-use reqwest::StatusCode;
+use reqwest::{Certificate, StatusCode};
 use serde_json::{value::Map, Value};
-use std::{cell::RefCell, collections::HashMap, time::Duration};
+use std::{cell::RefCell, collections::HashMap, sync::Arc, time::Duration};
+
+use super::{
+  definitions::{Definitions, SharedDefinitions},
+  metrics::Options,
+};
+
+/// Label names generated by `labels()`, they can't be redefined by the configuration
+pub const BUILTIN_LABELS: &[&str] = &["clu_name", "clu_id", "sdr_name", "sdr_id", "sdc_name", "sdc_id", "pdo_name", "pdo_id", "sto_name", "sto_id", "sds_name", "sds_id", "fs_name", "fs_id", "rcg_name", "rcg_id", "rpa_name", "rpa_id", "peer_name", "peer_id", "vol_name", "vol_id", "vol_type", "dev_name", "dev_id", "dev_path", "state"];
 
 #[derive(Debug, Default)]
 pub struct ClientInfo<'a> {
-  pub cfg_path: Option<&'a str>,
+  pub defs:     SharedDefinitions,
   pub ip:       Option<&'a str>,
   pub auth_usr: Option<&'a str>,
   pub auth_pwd: Option<&'a str>,
+  pub timeout:  Duration,
+  pub insecure: bool,
+  pub options:  Options,
+  ca_cert:      Option<Certificate>,
   token:        RefCell<Option<String>>,
 }
 
 impl<'a> ClientInfo<'a> {
-  pub fn new(cfg_path: Option<&'a str>, ip: Option<&'a str>, auth_usr: Option<&'a str>, auth_pwd: Option<&'a str>) -> ClientInfo<'a> {
-    ClientInfo { cfg_path,
-                 ip,
-                 auth_usr,
-                 auth_pwd,
-                 token: RefCell::new(None) }
+  pub fn new(defs: SharedDefinitions, ip: Option<&'a str>, auth_usr: Option<&'a str>, auth_pwd: Option<&'a str>, timeout: Duration, tls: &crate::config::Tls, options: Options) -> Result<ClientInfo<'a>, anyhow::Error> {
+    let ca_cert = match &tls.ca_cert {
+      None => None,
+      Some(f) => {
+        let pem = std::fs::read(f).map_err(|e| anyhow!("Failed to open file: {}, {:?}", f, e.kind()))?;
+        Some(Certificate::from_pem(&pem).map_err(|e| anyhow!("Invalid CA certificate {}: {}", f, e))?)
+      },
+    };
+
+    Ok(ClientInfo { defs,
+                    ip,
+                    auth_usr,
+                    auth_pwd,
+                    timeout,
+                    insecure: tls.insecure.unwrap_or(true),
+                    options,
+                    ca_cert,
+                    token: RefCell::new(None) })
+  }
+
+  /// Build the HTTP client used for the gateway requests
+  fn client(&self) -> reqwest::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().user_agent(env!("CARGO_PKG_NAME")).danger_accept_invalid_certs(self.insecure).timeout(self.timeout).connection_verbose(true);
+    if let Some(c) = &self.ca_cert {
+      builder = builder.add_root_certificate(c.clone());
+    }
+    builder.build()
   }
 
   async fn auth(&mut self) {
     trace!("auth");
     if self.token.borrow().is_none() {
-      if let Ok(c) = reqwest::Client::builder().user_agent(env!("CARGO_PKG_NAME")).danger_accept_invalid_certs(true).timeout(Duration::from_secs(10)).connection_verbose(true).build() {
+      if let Ok(c) = self.client() {
         if !self.auth_usr.unwrap().is_empty() && !self.auth_pwd.unwrap().is_empty() && self.token.borrow().is_none() {
           let req_url = format!("https://{ip}/api/login", ip = self.ip.unwrap());
           trace!("Auth on {:?} with {:?}/{:?}", req_url.clone(), self.auth_usr.unwrap().to_string(), self.auth_pwd.unwrap().to_string());
@@ -81,7 +114,7 @@ impl<'a> ClientInfo<'a> {
     trace!("version");
     self.auth().await;
 
-    if let Ok(c) = reqwest::Client::builder().user_agent(env!("CARGO_PKG_NAME")).danger_accept_invalid_certs(true).timeout(Duration::from_secs(15)).connection_verbose(true).build() {
+    if let Ok(c) = self.client() {
       if !self.auth_usr.unwrap().is_empty() && self.token.borrow().is_some() {
         let req_url = format!("https://{ip}/api/version", ip = self.ip.unwrap());
         let t = self.token.borrow().as_ref().unwrap().clone();
@@ -124,9 +157,37 @@ impl<'a> ClientInfo<'a> {
     }
   }
 
+  /// Invalidate the current auth token on the gateway
+  pub async fn logout(&mut self) {
+    trace!("logout");
+    if self.token.borrow().is_none() {
+      return;
+    }
+
+    if let Ok(c) = self.client() {
+      let req_url = format!("https://{ip}/api/logout", ip = self.ip.unwrap());
+      let t = self.token.borrow().as_ref().unwrap().clone();
+      trace!("Auth on {:?} with {:?}/{:?}", req_url.clone(), self.auth_usr, t);
+
+      let req = c.get(req_url).basic_auth(self.auth_usr.unwrap(), Some(t));
+      match req.send().await {
+        Ok(r) => {
+          trace!("resp:{:#?}", r);
+          match r.status() {
+            StatusCode::OK => info!("Logged out from {}", self.ip.unwrap()),
+            s => warn!("Logout from {} failed: {:?}", self.ip.unwrap(), s),
+          }
+        },
+        Err(e) => warn!("Logout request error: {:?}", e.to_string()),
+      }
+    }
+
+    *self.token.borrow_mut() = None;
+  }
+
   async fn instances(&mut self) -> Result<Map<String, serde_json::Value>, anyhow::Error> {
     trace!("instances");
-    if let Ok(c) = reqwest::Client::builder().user_agent(env!("CARGO_PKG_NAME")).danger_accept_invalid_certs(true).timeout(Duration::from_secs(15)).connection_verbose(true).build() {
+    if let Ok(c) = self.client() {
       if !self.auth_usr.unwrap().is_empty() && self.token.borrow().is_some() {
         let req_url = format!("https://{ip}/api/instances", ip = self.ip.unwrap());
         let t = self.token.borrow().as_ref().unwrap().clone();
@@ -141,7 +202,7 @@ impl<'a> ClientInfo<'a> {
                 match r.json::<serde_json::Value>().await {
                   Ok(t) => {
                     trace!("data: {:#?}", t);
-                    return Ok(t.as_object().unwrap().clone());
+                    Ok(t.as_object().unwrap().clone())
                   },
                   _ => Err(anyhow!("Failed to parse json")),
                 }
@@ -173,20 +234,94 @@ impl<'a> ClientInfo<'a> {
     }
   }
 
-  async fn stats(&mut self) -> Result<Map<String, serde_json::Value>, anyhow::Error> {
+  /// Active alerts of the cluster
+  async fn alerts(&mut self) -> Result<Vec<serde_json::Value>, anyhow::Error> {
+    trace!("alerts");
+    let c = self.client().map_err(|_| anyhow!("Cant build client"))?;
+    let t = self.token.borrow().clone().ok_or_else(|| anyhow!("Missing auth token"))?;
+    let req_url = format!("https://{ip}/api/types/Alert/instances", ip = self.ip.unwrap());
+    trace!("Auth on {:?} with {:?}/{:?}", req_url.clone(), self.auth_usr, t);
+
+    let r = c.get(req_url).basic_auth(self.auth_usr.unwrap(), Some(t)).send().await.map_err(|e| anyhow!("Alert request error: {:?}", e.to_string()))?;
+    trace!("resp:{:#?}", r);
+    match r.status() {
+      StatusCode::OK => {
+        match r.json::<serde_json::Value>().await {
+          Ok(Value::Array(a)) => Ok(a),
+          _ => Err(anyhow!("Failed to parse json")),
+        }
+      },
+      StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+        *self.token.borrow_mut() = None;
+        Err(anyhow!("Auth failed"))
+      },
+      _ => {
+        let msg: String = match r.json::<serde_json::Value>().await {
+          Ok(Value::Object(m)) => m.get("message").map(|m| m.to_string().replace('"', "")).unwrap_or_else(|| "unknown".to_string()),
+          _ => "unknown".to_string(),
+        };
+        Err(anyhow!("Unknown alert request error: {:?}", msg))
+      },
+    }
+  }
+
+  /// Run an object action (`/api/instances/Sds::<id>/action/querySdsNetworkLatencyMeters`...)
+  async fn action(&mut self, object: &str, action: &str) -> Result<serde_json::Value, anyhow::Error> {
+    trace!("action");
+    let c = self.client().map_err(|_| anyhow!("Cant build client"))?;
+    let t = self.token.borrow().clone().ok_or_else(|| anyhow!("Missing auth token"))?;
+    let req_url = format!("https://{ip}/api/instances/{object}/action/{action}", ip = self.ip.unwrap());
+    trace!("Auth on {:?} with {:?}/{:?}", req_url.clone(), self.auth_usr, t);
+
+    let r = c.post(req_url).basic_auth(self.auth_usr.unwrap(), Some(t)).json(&serde_json::json!({})).send().await.map_err(|e| anyhow!("{} request error: {:?}", action, e.to_string()))?;
+    trace!("resp:{:#?}", r);
+    match r.status() {
+      StatusCode::OK => r.json::<serde_json::Value>().await.map_err(|_| anyhow!("Failed to parse json")),
+      StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(anyhow!("Auth failed")),
+      _ => {
+        let msg: String = match r.json::<serde_json::Value>().await {
+          Ok(Value::Object(m)) => m.get("message").map(|m| m.to_string().replace('"', "")).unwrap_or_else(|| "unknown".to_string()),
+          _ => "unknown".to_string(),
+        };
+        Err(anyhow!("Unknown {} request error: {:?}", action, msg))
+      },
+    }
+  }
+
+  /// Run an action on each (not filtered) object of a list and store its result in the object under the action name
+  async fn meters(&mut self, instances: &mut Map<String, serde_json::Value>, list: &str, otype: &str, action: &str, labels: &HashMap<String, HashMap<&'static str, String>>) {
+    let ids: Vec<String> = instances.get(list).and_then(|v| v.as_array()).into_iter().flatten().filter_map(|o| o.get("id").and_then(|i| i.as_str())).filter(|id| labels.contains_key(*id)).map(|id| id.to_string()).collect();
+
+    let mut results: HashMap<String, serde_json::Value> = HashMap::new();
+    for id in ids {
+      match self.action(&format!("{otype}::{id}"), action).await {
+        Ok(v) => {
+          results.insert(id, v);
+        },
+        Err(e) => warn!("Failed to get {} of {} {}: {}", action, otype, id, e),
+      }
+    }
+    info!("Loaded {}: {}", action, results.len());
+
+    for o in instances.get_mut(list).and_then(|v| v.as_array_mut()).into_iter().flatten() {
+      if let Some(v) = o.get("id").and_then(|i| i.as_str()).and_then(|id| results.remove(id)) {
+        o[action] = v;
+      }
+    }
+  }
+
+  async fn stats(&mut self, query: &Map<String, serde_json::Value>) -> Result<Map<String, serde_json::Value>, anyhow::Error> {
     trace!("stats");
-    if let Ok(c) = reqwest::Client::builder().user_agent(env!("CARGO_PKG_NAME")).danger_accept_invalid_certs(true).timeout(Duration::from_secs(15)).connection_verbose(true).build() {
+    if let Ok(c) = self.client() {
       if !self.auth_usr.unwrap().is_empty() && self.token.borrow().is_some() {
         let req_url = format!("https://{ip}/api/instances/querySelectedStatistics", ip = self.ip.unwrap());
         let t = self.token.borrow().as_ref().unwrap().clone();
         trace!("Auth on {:?} with {:?}/{:?}", req_url.clone(), self.auth_usr, t);
 
-        let path = format!("{}{}", self.cfg_path.unwrap(), "/metric_query_selection.json");
-        let query = super::utils::read_json(&path).expect("Could not load the query (querySelectedStatistics)");
         trace!("query: {:#?}", query);
 
         let req = c.post(req_url).basic_auth(self.auth_usr.unwrap(), Some(t));
-        match req.json(&query).send().await {
+        match req.json(query).send().await {
           Ok(r) => {
             trace!("resp:{:#?}", r);
             match r.status() {
@@ -236,8 +371,8 @@ impl<'a> ClientInfo<'a> {
   fn relations(&mut self, instances: &Map<String, serde_json::Value>) -> Result<HashMap<&'static str, HashMap<String, HashMap<String, Vec<String>>>>, String> {
     trace!("relations");
     let mut relations: HashMap<&'static str, HashMap<String, HashMap<String, Vec<String>>>> = HashMap::new();
-    relations.entry("childs").or_insert_with(HashMap::new);
-    relations.entry("parents").or_insert_with(HashMap::new);
+    relations.entry("childs").or_default();
+    relations.entry("parents").or_default();
 
     // Get relations of all the elements
     for (key, value) in instances.iter() {
@@ -262,15 +397,15 @@ impl<'a> ClientInfo<'a> {
               continue;
             }
 
-            let parent_type: String = link["href"].to_string().split(':').next().unwrap().split('/').last().unwrap().to_string().replace('"', "").to_lowercase();
-            let parent_id: String = link["href"].to_string().split(':').last().unwrap().to_string().replace('"', "");
+            let parent_type: String = link["href"].to_string().split(':').next().unwrap().split('/').next_back().unwrap().to_string().replace('"', "").to_lowercase();
+            let parent_id: String = link["href"].to_string().split(':').next_back().unwrap().to_string().replace('"', "");
 
             {
-              let a = relations.get_mut("childs").unwrap().entry(parent_id.clone()).or_insert_with(HashMap::new).entry(item_type.clone()).or_insert_with(Vec::new);
+              let a = relations.get_mut("childs").unwrap().entry(parent_id.clone()).or_default().entry(item_type.clone()).or_default();
               a.push(item_id.clone());
             }
             {
-              let a = relations.get_mut("parents").unwrap().entry(item_id.clone()).or_insert_with(HashMap::new).entry(parent_type.clone()).or_insert_with(Vec::new);
+              let a = relations.get_mut("parents").unwrap().entry(item_id.clone()).or_default().entry(parent_type.clone()).or_default();
               a.push(parent_id.clone());
             }
           }
@@ -307,7 +442,7 @@ impl<'a> ClientInfo<'a> {
       label.entry("clu_name").or_insert_with(|| clu_name.to_string());
       label.entry("clu_id").or_insert_with(|| clu_id.to_string());
 
-      labels.entry("System").or_insert_with(HashMap::new).entry("System".to_string()).or_insert_with(|| label);
+      labels.entry("System").or_default().entry("System".to_string()).or_insert_with(|| label);
     }
     // Sdr
     for sdr in instances.get("sdrList").and_then(|v| v.as_array()).unwrap_or_else(|| {
@@ -325,7 +460,7 @@ impl<'a> ClientInfo<'a> {
         label.entry("sdr_name").or_insert_with(|| sdr_name);
         label.entry("sdr_id").or_insert_with(|| sdr_id.to_string());
 
-        labels.entry("sdr").or_insert_with(HashMap::new).entry(sdr_id).or_insert_with(|| label);
+        labels.entry("sdr").or_default().entry(sdr_id).or_insert_with(|| label);
       }
     }
     // Sdc
@@ -344,7 +479,7 @@ impl<'a> ClientInfo<'a> {
         label.entry("sdc_name").or_insert_with(|| sdc_name);
         label.entry("sdc_id").or_insert_with(|| sdc_id.to_string());
 
-        labels.entry("sdc").or_insert_with(HashMap::new).entry(sdc_id).or_insert_with(|| label);
+        labels.entry("sdc").or_default().entry(sdc_id).or_insert_with(|| label);
       }
     }
     // ProtectionDomain
@@ -363,7 +498,37 @@ impl<'a> ClientInfo<'a> {
         label.entry("pdo_name").or_insert_with(|| pdo_name.to_string());
         label.entry("pdo_id").or_insert_with(|| pdo_id.to_string());
 
-        labels.entry("protectiondomain").or_insert_with(HashMap::new).entry(pdo_id).or_insert_with(|| label);
+        labels.entry("protectiondomain").or_default().entry(pdo_id).or_insert_with(|| label);
+      }
+    }
+    // FaultSet (optional, only present on the clusters using fault sets)
+    for fsl in instances.get("faultSetList").and_then(|v| v.as_array()).unwrap_or_else(|| {
+                                                                         debug!("Failed to get 'faultSetList' from instances");
+                                                                         &default_val
+                                                                       })
+    {
+      for fs in fsl.as_object().iter() {
+        let mut label: HashMap<&'static str, String> = HashMap::new();
+        let fs_name = fs.get("name").map(|s| s.to_string().replace('"', "")).expect("fs_name Not found");
+        let fs_id = fs.get("id").map(|s| s.to_string().replace('"', "")).expect("fs_id Not found");
+
+        let pdo_id = match relations.get("parents").and_then(|p| p.get(&fs_id)).and_then(|p| p.get("protectiondomain")).and_then(|p| p.first()) {
+          Some(id) => id.to_string(),
+          None => {
+            warn!("Protection domain of the fault set {} ({}) not found, skipping it", fs_name, fs_id);
+            continue;
+          },
+        };
+        let pdo_name = labels.get("protectiondomain").and_then(|l| l.get(&pdo_id)).and_then(|l| l.get("pdo_name")).cloned().unwrap_or_default();
+
+        label.entry("clu_name").or_insert_with(|| clu_name.to_string());
+        label.entry("clu_id").or_insert_with(|| clu_id.to_string());
+        label.entry("fs_name").or_insert_with(|| fs_name);
+        label.entry("fs_id").or_insert_with(|| fs_id.to_string());
+        label.entry("pdo_name").or_insert(pdo_name);
+        label.entry("pdo_id").or_insert(pdo_id);
+
+        labels.entry("faultset").or_default().entry(fs_id).or_insert_with(|| label);
       }
     }
     // StoragePool
@@ -396,7 +561,7 @@ impl<'a> ClientInfo<'a> {
         label.entry("pdo_name").or_insert_with(|| parent["name"].to_string());
         label.entry("pdo_id").or_insert_with(|| parent["id"].to_string());
 
-        labels.entry("storagepool").or_insert_with(HashMap::new).entry(sp_id).or_insert_with(|| label);
+        labels.entry("storagepool").or_default().entry(sp_id).or_insert_with(|| label);
       }
     }
     // Sds
@@ -429,7 +594,13 @@ impl<'a> ClientInfo<'a> {
         label.entry("pdo_name").or_insert_with(|| parent["name"].to_string());
         label.entry("pdo_id").or_insert_with(|| parent["id"].to_string());
 
-        labels.entry("sds").or_insert_with(HashMap::new).entry(sds_id).or_insert_with(|| label);
+        // SDS outside of a fault set get empty values to keep the same label set
+        let fs_id = relations["parents"][&sds_id].get("faultset").and_then(|f| f.first()).cloned().unwrap_or_default();
+        let fs_name = labels.get("faultset").and_then(|f| f.get(&fs_id)).and_then(|f| f.get("fs_name")).cloned().unwrap_or_default();
+        label.entry("fs_name").or_insert(fs_name);
+        label.entry("fs_id").or_insert(fs_id);
+
+        labels.entry("sds").or_default().entry(sds_id).or_insert_with(|| label);
       }
     }
     // Volumes
@@ -476,7 +647,7 @@ impl<'a> ClientInfo<'a> {
         label.entry("pdo_name").or_insert_with(|| parent_pdo["name"].to_string());
         label.entry("pdo_id").or_insert_with(|| parent_pdo["id"].to_string());
 
-        labels.entry("volume").or_insert_with(HashMap::new).entry(vol_id).or_insert_with(|| label);
+        labels.entry("volume").or_default().entry(vol_id).or_insert_with(|| label);
       }
     }
     // Devices
@@ -590,7 +761,76 @@ impl<'a> ClientInfo<'a> {
           },
         }
 
-        labels.entry("device").or_insert_with(HashMap::new).entry(dev_id).or_insert_with(|| label);
+        labels.entry("device").or_default().entry(dev_id).or_insert_with(|| label);
+      }
+    }
+    // PeerMdm (optional, only present on the clusters using replication)
+    for pml in instances.get("peerMdmList").and_then(|v| v.as_array()).unwrap_or_else(|| {
+                                                                         debug!("Failed to get 'peerMdmList' from instances");
+                                                                         &default_val
+                                                                       })
+    {
+      for peer in pml.as_object().iter() {
+        let mut label: HashMap<&'static str, String> = HashMap::new();
+        let peer_name = peer.get("name").map(|s| s.to_string().replace('"', "")).expect("peer_name Not found");
+        let peer_id = peer.get("id").map(|s| s.to_string().replace('"', "")).expect("peer_id Not found");
+
+        label.entry("clu_name").or_insert_with(|| clu_name.to_string());
+        label.entry("clu_id").or_insert_with(|| clu_id.to_string());
+        label.entry("peer_name").or_insert_with(|| peer_name);
+        label.entry("peer_id").or_insert_with(|| peer_id.to_string());
+
+        labels.entry("peermdm").or_default().entry(peer_id).or_insert_with(|| label);
+      }
+    }
+    // ReplicationConsistencyGroup (optional)
+    for rcgl in instances.get("replicationConsistencyGroupList").and_then(|v| v.as_array()).unwrap_or_else(|| {
+                                                                                             debug!("Failed to get 'replicationConsistencyGroupList' from instances");
+                                                                                             &default_val
+                                                                                           })
+    {
+      for rcg in rcgl.as_object().iter() {
+        let mut label: HashMap<&'static str, String> = HashMap::new();
+        let rcg_name = rcg.get("name").map(|s| s.to_string().replace('"', "")).expect("rcg_name Not found");
+        let rcg_id = rcg.get("id").map(|s| s.to_string().replace('"', "")).expect("rcg_id Not found");
+        let pdo_id = rcg.get("protectionDomainId").and_then(|s| s.as_str()).unwrap_or_default().to_string();
+        let peer_id = rcg.get("peerMdmId").and_then(|s| s.as_str()).unwrap_or_default().to_string();
+
+        label.entry("clu_name").or_insert_with(|| clu_name.to_string());
+        label.entry("clu_id").or_insert_with(|| clu_id.to_string());
+        label.entry("rcg_name").or_insert_with(|| rcg_name);
+        label.entry("rcg_id").or_insert_with(|| rcg_id.to_string());
+        label.entry("pdo_name").or_insert_with(|| labels.get("protectiondomain").and_then(|l| l.get(&pdo_id)).and_then(|l| l.get("pdo_name")).cloned().unwrap_or_default());
+        label.entry("pdo_id").or_insert(pdo_id);
+        label.entry("peer_name").or_insert_with(|| labels.get("peermdm").and_then(|l| l.get(&peer_id)).and_then(|l| l.get("peer_name")).cloned().unwrap_or_default());
+        label.entry("peer_id").or_insert(peer_id);
+
+        labels.entry("replicationconsistencygroup").or_default().entry(rcg_id).or_insert_with(|| label);
+      }
+    }
+    // ReplicationPair (optional)
+    for rpl in instances.get("replicationPairList").and_then(|v| v.as_array()).unwrap_or_else(|| {
+                                                                                 debug!("Failed to get 'replicationPairList' from instances");
+                                                                                 &default_val
+                                                                               })
+    {
+      for rpa in rpl.as_object().iter() {
+        let mut label: HashMap<&'static str, String> = HashMap::new();
+        let rpa_name = rpa.get("name").map(|s| s.to_string().replace('"', "")).expect("rpa_name Not found");
+        let rpa_id = rpa.get("id").map(|s| s.to_string().replace('"', "")).expect("rpa_id Not found");
+        let rcg_id = rpa.get("replicationConsistencyGroupId").and_then(|s| s.as_str()).unwrap_or_default().to_string();
+        let vol_id = rpa.get("localVolumeId").and_then(|s| s.as_str()).unwrap_or_default().to_string();
+
+        label.entry("clu_name").or_insert_with(|| clu_name.to_string());
+        label.entry("clu_id").or_insert_with(|| clu_id.to_string());
+        label.entry("rpa_name").or_insert_with(|| rpa_name);
+        label.entry("rpa_id").or_insert_with(|| rpa_id.to_string());
+        label.entry("rcg_name").or_insert_with(|| labels.get("replicationconsistencygroup").and_then(|l| l.get(&rcg_id)).and_then(|l| l.get("rcg_name")).cloned().unwrap_or_default());
+        label.entry("rcg_id").or_insert(rcg_id);
+        label.entry("vol_name").or_insert_with(|| labels.get("volume").and_then(|l| l.get(&vol_id)).and_then(|l| l.get("vol_name")).cloned().unwrap_or_default());
+        label.entry("vol_id").or_insert(vol_id);
+
+        labels.entry("replicationpair").or_default().entry(rpa_id).or_insert_with(|| label);
       }
     }
 
@@ -602,8 +842,35 @@ impl<'a> ClientInfo<'a> {
     }
   }
 
+  /// Add the configured instance fields (`[fields.<type>]`) to the labels of each object
+  fn field_labels(&self, instances: &Map<String, serde_json::Value>, labels: &mut HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>) {
+    trace!("field_labels");
+    for (stype, fields) in self.options.fields.iter() {
+      let objects: Vec<&serde_json::Value> = if stype == "system" {
+        instances.get("System").into_iter().collect()
+      } else {
+        instances.iter().filter(|(k, _)| super::filters::list_type(k) == *stype).filter_map(|(_, v)| v.as_array()).flatten().collect()
+      };
+
+      for o in objects {
+        let label = match o.get("id").and_then(|i| i.as_str()) {
+          _ if stype == "system" => labels.get_mut("System").and_then(|l| l.get_mut("System")),
+          Some(id) => labels.get_mut(stype.as_str()).and_then(|l| l.get_mut(id)),
+          None => None,
+        };
+        if let Some(label) = label {
+          for (k, path) in fields.iter() {
+            label.entry(super::utils::intern(k)).or_insert_with(|| super::utils::json_path_str(o, path));
+          }
+        }
+      }
+    }
+  }
+
   // pub fn metrics(&mut self) -> Option<Vec<Metric>> {
   pub async fn metrics(&mut self) -> Option<Vec<super::metrics::Metric>> {
+    // Use the same definitions for the whole cycle even if they are reloaded meanwhile
+    let defs: Arc<Definitions> = self.defs.read().unwrap().clone();
     self.auth().await;
 
     let inst = self.instances().await;
@@ -617,18 +884,119 @@ impl<'a> ClientInfo<'a> {
       return None;
     }
 
-    let labels = self.labels(inst.as_ref().unwrap(), rela.as_ref().unwrap());
+    let mut labels = self.labels(inst.as_ref().unwrap(), rela.as_ref().unwrap());
     if labels.is_err() {
       return None;
     }
+    self.field_labels(inst.as_ref().unwrap(), labels.as_mut().unwrap());
+    super::rules::apply(&self.options.rules, labels.as_mut().unwrap());
+    for l in labels.as_mut().unwrap().values_mut().flat_map(|l| l.values_mut()) {
+      for (k, v) in self.options.labels.iter() {
+        l.entry(super::utils::intern(k)).or_insert_with(|| v.to_string());
+      }
+    }
     info!("Loaded labels: {:?}", &labels.as_ref().unwrap().keys().collect::<Vec<_>>());
 
-    let stats = self.stats().await;
+    // Drop the filtered objects and only query the statistics of the remaining ones
+    let query = if self.options.filters.is_empty() {
+      defs.query.clone()
+    } else {
+      let excluded = super::filters::excluded(&self.options.filters, inst.as_ref().unwrap(), rela.as_ref().unwrap());
+      for l in labels.as_mut().unwrap().values_mut() {
+        l.retain(|id, _| !excluded.contains(id));
+      }
+      info!("Filtered out objects: {}", excluded.len());
+      super::filters::narrow_query(&defs.query, &self.options.filters, inst.as_ref().unwrap(), &excluded)
+    };
+
+    // Opt-in per object meters (one request per object)
+    let mut inst = inst;
+    if self.options.sds_network_latency {
+      let sds = labels.as_ref().unwrap().get("sds").cloned().unwrap_or_default();
+      self.meters(inst.as_mut().unwrap(), "sdsList", "Sds", "querySdsNetworkLatencyMeters", &sds).await;
+    }
+    if self.options.device_latency {
+      let sds = labels.as_ref().unwrap().get("sds").cloned().unwrap_or_default();
+      self.meters(inst.as_mut().unwrap(), "sdsList", "Sds", "queryDeviceLatencyMeters", &sds).await;
+    }
+
+    let stats = self.stats(&query).await;
     if stats.is_err() {
       return None;
     }
     info!("Loaded stats: {:?}", stats.as_ref().unwrap().keys().collect::<Vec<_>>());
 
-    super::metrics::get(self.cfg_path, &inst, &stats, &labels, &rela)
+    // The other metrics are still exposed if the alerts can't be fetched, the alert series are then missing (not 0)
+    let alerts = match self.alerts().await {
+      Ok(a) => {
+        info!("Loaded alerts: {}", a.len());
+        Some(a)
+      },
+      Err(e) => {
+        warn!("Failed to get the alerts: {}", e);
+        None
+      },
+    };
+
+    super::metrics::get(&defs, &self.options, &inst, &stats, &labels, alerts.as_deref())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  type Relations = HashMap<&'static str, HashMap<String, HashMap<String, Vec<String>>>>;
+
+  /// Instances of a cluster with one protection domain and the given extra lists
+  fn instances(extra: Value) -> Map<String, Value> {
+    let mut i = json!({ "System": { "id": "c1", "name": "clu1" }, "protectionDomainList": [{ "id": "pd1", "name": "pd" }] }).as_object().unwrap().clone();
+    i.extend(extra.as_object().unwrap().clone());
+    i
+  }
+
+  /// Parent relations: child id -> parent type -> parent ids
+  fn relations(parents: &[(&str, &str, &str)]) -> Relations {
+    let mut r: Relations = HashMap::from([("childs", HashMap::new()), ("parents", HashMap::new())]);
+    for (id, t, parent) in parents {
+      r.get_mut("parents").unwrap().entry(id.to_string()).or_default().entry(t.to_string()).or_default().push(parent.to_string());
+    }
+    r
+  }
+
+  #[test]
+  fn faultset_labels() {
+    let i = instances(json!({ "faultSetList": [{ "id": "fs1", "name": "rack1" }, { "id": "fs2", "name": "orphan" }] }));
+    let labels = ClientInfo::default().labels(&i, &relations(&[("fs1", "protectiondomain", "pd1")])).unwrap();
+    let fs = &labels["faultset"];
+    assert_eq!(fs["fs1"]["fs_name"], "rack1");
+    assert_eq!(fs["fs1"]["pdo_name"], "pd");
+    assert_eq!(fs["fs1"]["pdo_id"], "pd1");
+    assert!(!fs.contains_key("fs2"));
+  }
+
+  #[test]
+  fn replication_labels() {
+    let i = instances(json!({ "storagePoolList": [{ "id": "sp1", "name": "pool1" }],
+                              "volumeList": [{ "id": "v1", "name": "data", "volumeType": "ThinProvisioned" }],
+                              "peerMdmList": [{ "id": "pm1", "name": "remote" }],
+                              "replicationConsistencyGroupList": [{ "id": "rcg1", "name": "group", "protectionDomainId": "pd1", "peerMdmId": "pm1" }, { "id": "rcg2", "name": "alone" }],
+                              "replicationPairList": [{ "id": "rp1", "name": "pair", "replicationConsistencyGroupId": "rcg1", "localVolumeId": "v1" }, { "id": "rp2", "name": "lost", "replicationConsistencyGroupId": "rcg9", "localVolumeId": "v9" }] }));
+    let labels = ClientInfo::default().labels(&i, &relations(&[("sp1", "protectiondomain", "pd1"), ("v1", "storagepool", "sp1")])).unwrap();
+
+    assert_eq!(labels["peermdm"]["pm1"], HashMap::from([("clu_name", "clu1".to_string()), ("clu_id", "c1".to_string()), ("peer_name", "remote".to_string()), ("peer_id", "pm1".to_string())]));
+
+    let rcg = &labels["replicationconsistencygroup"];
+    assert_eq!(rcg["rcg1"]["rcg_name"], "group");
+    assert_eq!(rcg["rcg1"]["pdo_name"], "pd");
+    assert_eq!(rcg["rcg1"]["peer_name"], "remote");
+    assert_eq!((rcg["rcg2"]["pdo_id"].as_str(), rcg["rcg2"]["pdo_name"].as_str(), rcg["rcg2"]["peer_name"].as_str()), ("", "", ""));
+
+    let rpa = &labels["replicationpair"];
+    assert_eq!(rpa["rp1"]["rpa_name"], "pair");
+    assert_eq!(rpa["rp1"]["rcg_name"], "group");
+    assert_eq!(rpa["rp1"]["vol_name"], "data");
+    assert_eq!((rpa["rp2"]["rcg_id"].as_str(), rpa["rp2"]["rcg_name"].as_str(), rpa["rp2"]["vol_name"].as_str()), ("rcg9", "", ""));
   }
 }
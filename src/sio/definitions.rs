@@ -0,0 +1,814 @@
+//! Metric definitions loaded from `metric_definition.json`, `metric_query_selection.json`, `derived_metrics.json`, `state_definition.json` and `info_definition.json`
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::{value::Map, Value};
+use super::expr::Expr;
+use std::{
+  collections::HashMap,
+  fmt,
+  path::Path,
+  sync::{Arc, RwLock},
+  time::SystemTime,
+};
+
+pub const METRIC_DEFINITION: &str = "metric_definition.json";
+pub const METRIC_QUERY_SELECTION: &str = "metric_query_selection.json";
+pub const DERIVED_METRICS: &str = "derived_metrics.json";
+pub const STATE_DEFINITION: &str = "state_definition.json";
+pub const INFO_DEFINITION: &str = "info_definition.json";
+
+/// Definition files embedded in the binary
+pub struct Profile {
+  pub name:                   &'static str,
+  pub metric_definition:      &'static str,
+  pub metric_query_selection: &'static str,
+  pub derived_metrics:        &'static str,
+  pub state_definition:       &'static str,
+  pub info_definition:        &'static str,
+}
+
+/// Profile used when none is selected
+pub const DEFAULT_PROFILE: &str = "default";
+
+pub const PROFILES: &[Profile] = &[Profile { name:                   DEFAULT_PROFILE,
+                                             metric_definition:      include_str!("../../cfg/metric_definition.json"),
+                                             metric_query_selection: include_str!("../../cfg/metric_query_selection.json"),
+                                             derived_metrics:        include_str!("../../cfg/derived_metrics.json"),
+                                             state_definition:       include_str!("../../cfg/state_definition.json"),
+                                             info_definition:        include_str!("../../cfg/info_definition.json"), },
+                                   Profile { name:                   "3.6",
+                                             metric_definition:      include_str!("../../cfg/3.6/metric_definition.json"),
+                                             metric_query_selection: include_str!("../../cfg/3.6/metric_query_selection.json"),
+                                             derived_metrics:        include_str!("../../cfg/3.6/derived_metrics.json"),
+                                             state_definition:       include_str!("../../cfg/3.6/state_definition.json"),
+                                             info_definition:        include_str!("../../cfg/3.6/info_definition.json"), }];
+
+/// Definitions shared between the collector and the reloader
+pub type SharedDefinitions = Arc<RwLock<Arc<Definitions>>>;
+
+/// Per object type (lowercase) metric definition overrides
+pub type TypeDefs = HashMap<String, HashMap<String, Option<MetricDef>>>;
+
+/// Where the definitions come from: an embedded profile and an optional configuration path
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Source {
+  pub profile:  String,
+  pub cfg_path: Option<String>,
+  /// Merge the files found in `cfg_path` on top of the profile instead of replacing it
+  pub overlay:  bool,
+}
+impl fmt::Display for Source {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self.cfg_path {
+      None => write!(f, "{}", self.profile),
+      Some(p) if self.overlay => write!(f, "{}+{}", self.profile, p),
+      Some(p) => write!(f, "{}", p),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricDef {
+  pub name:  String,
+  #[serde(rename = "type")]
+  pub mtype: String,
+  pub help:  String,
+  /// Prometheus base unit (bytes, seconds...) the value is converted to
+  pub unit:  Option<String>,
+  /// Factor applied to the value to convert it to `unit`
+  pub scale: Option<f64>,
+}
+
+/// Metric computed from an expression over the other properties of the same object
+#[derive(Debug, Clone)]
+pub struct DerivedDef {
+  pub name:  String,
+  pub mtype: String,
+  pub help:  String,
+  pub unit:  Option<String>,
+  pub expr:  Expr,
+}
+
+/// Layout of a `derived_metrics.json` entry
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DerivedEntry {
+  #[serde(rename = "type", default = "default_gauge")]
+  mtype: String,
+  help:  Option<String>,
+  unit:  Option<String>,
+  expr:  String,
+}
+fn default_gauge() -> String { "gauge".to_string() }
+
+/// Numeric mapping of an instance state field
+#[derive(Debug, Clone)]
+pub struct StateDef {
+  /// Object type (lowercase, `system` for the System)
+  pub otype:   String,
+  /// Instance field or json path (`mdmCluster.clusterState`)
+  pub field:   String,
+  /// Prefix of the metric name and object type of its subsystem, the object type unless overridden (`mdm` for `mdm_cluster_state`)
+  pub prefix:  String,
+  pub name:    String,
+  pub help:    String,
+  /// Known states and their value, ordered by value, empty for the numeric and boolean (0 / 1) fields
+  pub values:  Vec<(String, f64)>,
+  /// Value of the states not in `values`, the series is dropped if not defined
+  pub unknown: Option<f64>,
+  /// Factor applied to the numeric fields (percentages to ratios...)
+  pub scale:   Option<f64>,
+}
+
+/// Layout of a `state_definition.json` entry
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StateEntry {
+  prefix:  Option<String>,
+  name:    String,
+  help:    Option<String>,
+  #[serde(default)]
+  values:  HashMap<String, f64>,
+  unknown: Option<f64>,
+  scale:   Option<f64>,
+}
+
+/// Labels of the `<type>_info` metric: label name -> instance field or json path, per object type (lowercase)
+pub type InfoDefs = HashMap<String, Vec<(String, String)>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct Definitions {
+  pub metrics: HashMap<String, MetricDef>,
+  /// Per object type (lowercase) overrides, `None` disables the property for that type
+  pub types:   TypeDefs,
+  pub query:   Map<String, Value>,
+  /// Derived metrics per object type (lowercase)
+  pub derived: HashMap<String, Vec<DerivedDef>>,
+  pub states:  Vec<StateDef>,
+  pub infos:   InfoDefs,
+}
+
+impl Definitions {
+  /// Load and validate both definition files from the embedded profile and `cfg_path`
+  pub fn load(source: &Source) -> Result<Definitions> {
+    let profile = PROFILES.iter().find(|p| p.name == source.profile).ok_or_else(|| anyhow!("Unknown profile: {} (available: {})", source.profile, PROFILES.iter().map(|p| p.name).collect::<Vec<_>>().join(", ")))?;
+    let mut mdef: Map<String, Value> = serde_json::from_str(profile.metric_definition).map_err(|e| anyhow!("Profile {}: invalid {METRIC_DEFINITION}: {e}", source.profile))?;
+    let mut query: Map<String, Value> = serde_json::from_str(profile.metric_query_selection).map_err(|e| anyhow!("Profile {}: invalid {METRIC_QUERY_SELECTION}: {e}", source.profile))?;
+    let mut derived: Map<String, Value> = serde_json::from_str(profile.derived_metrics).map_err(|e| anyhow!("Profile {}: invalid {DERIVED_METRICS}: {e}", source.profile))?;
+    let mut states: Map<String, Value> = serde_json::from_str(profile.state_definition).map_err(|e| anyhow!("Profile {}: invalid {STATE_DEFINITION}: {e}", source.profile))?;
+    let mut infos: Map<String, Value> = serde_json::from_str(profile.info_definition).map_err(|e| anyhow!("Profile {}: invalid {INFO_DEFINITION}: {e}", source.profile))?;
+
+    if let Some(cfg_path) = &source.cfg_path {
+      let f = Path::new(cfg_path).join(METRIC_DEFINITION);
+      if f.exists() {
+        let user = super::utils::read_json(&f.to_string_lossy())?;
+        if source.overlay {
+          overlay_metrics(&mut mdef, user);
+        } else {
+          mdef = user;
+        }
+      }
+
+      let f = Path::new(cfg_path).join(METRIC_QUERY_SELECTION);
+      if f.exists() {
+        let user = super::utils::read_json(&f.to_string_lossy())?;
+        if source.overlay {
+          overlay_query(&mut query, user)?;
+        } else {
+          query = user;
+        }
+      }
+
+      let f = Path::new(cfg_path).join(DERIVED_METRICS);
+      if f.exists() {
+        let user = super::utils::read_json(&f.to_string_lossy())?;
+        if source.overlay {
+          overlay_blocks(&mut derived, user);
+        } else {
+          derived = user;
+        }
+      }
+
+      let f = Path::new(cfg_path).join(STATE_DEFINITION);
+      if f.exists() {
+        let user = super::utils::read_json(&f.to_string_lossy())?;
+        if source.overlay {
+          overlay_blocks(&mut states, user);
+        } else {
+          states = user;
+        }
+      }
+
+      let f = Path::new(cfg_path).join(INFO_DEFINITION);
+      if f.exists() {
+        let user = super::utils::read_json(&f.to_string_lossy())?;
+        if source.overlay {
+          overlay_blocks(&mut infos, user);
+        } else {
+          infos = user;
+        }
+      }
+    }
+
+    let (metrics, types) = parse_metrics(mdef)?;
+    let defs = Definitions { metrics,
+                             types,
+                             query,
+                             derived: parse_derived(derived)?,
+                             states: parse_states(states)?,
+                             infos: parse_infos(infos)? };
+    defs.validate_query()?;
+    Ok(defs)
+  }
+
+  /// Definition of a property for an object type, the type overrides take precedence over the flat entries
+  ///
+  /// Returns `None` if the property is not defined and `Some(None)` if it is disabled for this type
+  pub fn metric(&self, stype: &str, prop: &str) -> Option<Option<&MetricDef>> {
+    match self.types.get(&stype.to_lowercase()).and_then(|t| t.get(prop)) {
+      Some(d) => Some(d.as_ref()),
+      None => self.metrics.get(prop).map(Some),
+    }
+  }
+
+  /// Object types and properties requested by `metric_query_selection.json`
+  pub fn selection(&self) -> Vec<(&str, Vec<&str>)> {
+    self.query
+        .get("selectedStatisticsList")
+        .and_then(|l| l.as_array())
+        .map(|l| {
+          l.iter()
+           .filter_map(|s| {
+             let stype = s.get("type")?.as_str()?;
+             let props = s.get("properties")?.as_array()?.iter().filter_map(|p| p.as_str()).collect();
+             Some((stype, props))
+           })
+           .collect()
+        })
+        .unwrap_or_default()
+  }
+
+  /// Cross-check the query selection against the metric definitions, returns the problems found (the metric names are compared fully-qualified, with the namespace and the subsystem of their object type)
+  pub fn check(&self, opts: &super::metrics::Options) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    let mut outputs: HashMap<String, String> = HashMap::new();
+
+    for name in super::metrics::builtin_names(opts) {
+      if !valid_metric_name(&name) {
+        problems.push(format!("The collector generates an invalid metric name ({name})"));
+      }
+      outputs.insert(name, "the collector".to_string());
+    }
+    for (k, d) in self.metrics.iter() {
+      if !valid_metric_name(&d.name) {
+        problems.push(format!("{METRIC_DEFINITION}: {k} has an invalid metric name ({})", d.name));
+      }
+    }
+    for (t, defs) in self.types.iter() {
+      for (k, d) in defs.iter().filter_map(|(k, d)| d.as_ref().map(|d| (k, d))) {
+        if !valid_metric_name(&d.name) {
+          problems.push(format!("{METRIC_DEFINITION}: {k} ({t}) has an invalid metric name ({})", d.name));
+        }
+      }
+    }
+
+    let selection = self.selection();
+    for (stype, props) in selection.iter() {
+      let prefix = if *stype == "System" { stype.to_string() } else { stype.to_lowercase() };
+      let mut seen: Vec<&str> = Vec::new();
+
+      for &p in props {
+        if seen.contains(&p) {
+          problems.push(format!("{METRIC_QUERY_SELECTION}: {p} ({stype}) is selected more than once"));
+          continue;
+        }
+        seen.push(p);
+
+        let mdef = match self.metric(stype, p) {
+          None => {
+            problems.push(format!("{METRIC_QUERY_SELECTION}: {p} ({stype}) not found in ({METRIC_DEFINITION})"));
+            continue;
+          },
+          Some(None) => continue,
+          Some(Some(d)) => d,
+        };
+
+        for name in super::metrics::output_names(&prefix, p, mdef, opts).into_iter().map(|n| opts.fq_name(stype, &n)) {
+          if !valid_metric_name(&name) {
+            problems.push(format!("{p} ({stype}) generates an invalid metric name ({name})"));
+          }
+          match outputs.get(&name) {
+            Some(other) => problems.push(format!("{p} ({stype}) generates the metric {name} already generated by {other}")),
+            None => {
+              outputs.insert(name, format!("{p} ({stype})"));
+            },
+          }
+        }
+      }
+    }
+
+    for (t, defs) in self.derived.iter() {
+      let props = selection.iter().find(|(s, _)| s.to_lowercase() == *t).map(|(_, p)| p.as_slice()).unwrap_or_default();
+      let prefix = if t == "system" { "System" } else { t.as_str() };
+
+      for d in defs {
+        let name = opts.fq_name(t, &super::metrics::metric_name(prefix, &d.name, ""));
+        if !valid_metric_name(&name) {
+          problems.push(format!("{DERIVED_METRICS}: {} ({t}) generates an invalid metric name ({name})", d.name));
+        }
+        if let Some(v) = d.expr.vars().into_iter().find(|v| !props.contains(&v.split('.').next().unwrap_or_default())) {
+          problems.push(format!("{DERIVED_METRICS}: {} ({t}) uses {v} which is not selected in ({METRIC_QUERY_SELECTION})", d.name));
+        }
+        match outputs.get(&name) {
+          Some(other) => problems.push(format!("{DERIVED_METRICS}: {} ({t}) generates the metric {name} already generated by {other}", d.name)),
+          None => {
+            outputs.insert(name, format!("{} ({t})", d.name));
+          },
+        }
+      }
+    }
+
+    for d in self.states.iter() {
+      let name = opts.fq_name(&d.prefix, &super::metrics::metric_name(&d.prefix, &d.name, ""));
+      if !valid_metric_name(&name) {
+        problems.push(format!("{STATE_DEFINITION}: {} ({}) generates an invalid metric name ({name})", d.field, d.otype));
+      }
+      match outputs.get(&name) {
+        Some(other) => problems.push(format!("{STATE_DEFINITION}: {} ({}) generates the metric {name} already generated by {other}", d.field, d.otype)),
+        None => {
+          outputs.insert(name, format!("{} ({})", d.field, d.otype));
+        },
+      }
+    }
+
+    for t in self.infos.keys() {
+      let name = opts.fq_name(t, &super::metrics::metric_name(t, "info", ""));
+      if let Some(other) = outputs.get(&name) {
+        problems.push(format!("{INFO_DEFINITION}: {t} generates the metric {name} already generated by {other}"));
+      }
+    }
+    problems.append(&mut self.label_problems(opts));
+
+    problems.sort();
+    problems
+  }
+
+  /// Info labels colliding with the constant, field or rule labels of the configuration, checked at startup and on reload
+  pub fn label_problems(&self, opts: &super::metrics::Options) -> Vec<String> {
+    let rule_labels: Vec<String> = opts.rules.iter().flat_map(|r| r.label_names()).collect();
+    let mut problems: Vec<String> = Vec::new();
+
+    for (t, labels) in self.infos.iter() {
+      for (label, _) in labels {
+        if opts.labels.contains_key(label) || opts.fields.get(t).is_some_and(|f| f.contains_key(label)) || rule_labels.contains(label) {
+          problems.push(format!("{INFO_DEFINITION}: {label} ({t}) collides with a constant, field or rule label of the configuration"));
+        }
+      }
+    }
+    problems.sort();
+    problems
+  }
+
+  fn validate_query(&self) -> Result<()> {
+    let list = self.query.get("selectedStatisticsList").and_then(|l| l.as_array()).ok_or_else(|| anyhow!("{METRIC_QUERY_SELECTION}: missing 'selectedStatisticsList' array"))?;
+
+    for (i, s) in list.iter().enumerate() {
+      let stype = s.get("type").and_then(|t| t.as_str()).ok_or_else(|| anyhow!("{METRIC_QUERY_SELECTION}: entry {i} has no 'type'"))?;
+      if s.get("allIds").is_none() && s.get("ids").is_none() {
+        return Err(anyhow!("{METRIC_QUERY_SELECTION}: {stype} has neither 'allIds' nor 'ids'"));
+      }
+      let props = s.get("properties").and_then(|p| p.as_array()).ok_or_else(|| anyhow!("{METRIC_QUERY_SELECTION}: {stype} has no 'properties' array"))?;
+      if let Some(p) = props.iter().find(|p| !p.is_string()) {
+        return Err(anyhow!("{METRIC_QUERY_SELECTION}: {stype} has a non string property: {p}"));
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Merge a user query selection on top of the profile one, adding the missing types and properties
+fn overlay_query(query: &mut Map<String, Value>, user: Map<String, Value>) -> Result<()> {
+  let user_list = match user.get("selectedStatisticsList").and_then(|l| l.as_array()) {
+    None => return Err(anyhow!("{METRIC_QUERY_SELECTION}: missing 'selectedStatisticsList' array")),
+    Some(l) => l,
+  };
+  let list = query.get_mut("selectedStatisticsList").and_then(|l| l.as_array_mut()).ok_or_else(|| anyhow!("{METRIC_QUERY_SELECTION}: missing 'selectedStatisticsList' array"))?;
+
+  for u in user_list {
+    let stype = u.get("type").and_then(|t| t.as_str()).ok_or_else(|| anyhow!("{METRIC_QUERY_SELECTION}: entry without 'type'"))?;
+    let base = match list.iter_mut().find(|s| s.get("type").and_then(|t| t.as_str()) == Some(stype)).and_then(|s| s.as_object_mut()) {
+      None => {
+        list.push(u.clone());
+        continue;
+      },
+      Some(b) => b,
+    };
+
+    for (k, v) in u.as_object().unwrap() {
+      if k == "properties" {
+        let props = base.entry("properties").or_insert_with(|| Value::Array(Vec::new())).as_array_mut().ok_or_else(|| anyhow!("{METRIC_QUERY_SELECTION}: {stype} has no 'properties' array"))?;
+        for p in v.as_array().into_iter().flatten() {
+          if !props.contains(p) {
+            props.push(p.clone());
+          }
+        }
+      } else {
+        if k == "ids" {
+          base.remove("allIds");
+        } else if k == "allIds" {
+          base.remove("ids");
+        }
+        base.insert(k.to_string(), v.clone());
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Merge user metric definitions on top of the profile ones, merging the type override blocks
+fn overlay_metrics(mdef: &mut Map<String, Value>, user: Map<String, Value>) {
+  for (k, v) in user {
+    match (mdef.get_mut(&k), v) {
+      (Some(Value::Object(base)), Value::Object(u)) if is_type_block(base) && is_type_block(&u) => base.extend(u),
+      (_, v) => {
+        mdef.insert(k, v);
+      },
+    }
+  }
+}
+
+/// Merge user derived metrics / states on top of the profile ones, per object type and entry (`false` removes an entry)
+fn overlay_blocks(base: &mut Map<String, Value>, user: Map<String, Value>) {
+  for (t, v) in user {
+    match (base.get_mut(&t), v) {
+      (Some(Value::Object(base)), Value::Object(u)) => {
+        for (k, d) in u {
+          if d == Value::Bool(false) {
+            base.remove(&k);
+          } else {
+            base.insert(k, d);
+          }
+        }
+      },
+      (_, v) => {
+        base.insert(t, v);
+      },
+    }
+  }
+}
+
+/// Type override blocks are the entries that are not a metric definition (no `name`)
+fn is_type_block(v: &Map<String, Value>) -> bool { !v.contains_key("name") }
+
+/// Parse and validate the `metric_definition.json` entries and type override blocks
+fn parse_metrics(mdef: Map<String, Value>) -> Result<(HashMap<String, MetricDef>, TypeDefs)> {
+  let mut metrics: HashMap<String, MetricDef> = HashMap::new();
+  let mut types: TypeDefs = HashMap::new();
+
+  for (k, v) in mdef {
+    match v {
+      Value::Object(block) if is_type_block(&block) => {
+        let defs = types.entry(k.to_lowercase()).or_default();
+        for (p, d) in block {
+          // `false` or `"disabled": true`, the key is removed before the strict deserialization of the definition
+          let (disabled, d) = match d {
+            Value::Bool(false) => (true, d),
+            Value::Object(mut o) => (o.remove("disabled").and_then(|d| d.as_bool()).unwrap_or(false), Value::Object(o)),
+            d => (false, d),
+          };
+          let d = if disabled { None } else { Some(parse_metric(&format!("{p} ({k})"), d)?) };
+          defs.insert(p, d);
+        }
+      },
+      v => {
+        let d = parse_metric(&k, v)?;
+        metrics.insert(k, d);
+      },
+    }
+  }
+  Ok((metrics, types))
+}
+
+/// Parse and validate a single metric definition
+fn parse_metric(k: &str, v: Value) -> Result<MetricDef> {
+  let d: MetricDef = serde_json::from_value(v).map_err(|e| anyhow!("{METRIC_DEFINITION}: invalid definition of {k}: {e}"))?;
+  if d.name.is_empty() {
+    return Err(anyhow!("{METRIC_DEFINITION}: {k} has an empty name"));
+  }
+  if !matches!(d.mtype.to_lowercase().as_str(), "counter" | "gauge") {
+    return Err(anyhow!("{METRIC_DEFINITION}: {k} has an unknown type ({})", d.mtype));
+  }
+  if d.scale.is_some() && d.unit.is_none() {
+    return Err(anyhow!("{METRIC_DEFINITION}: {k} has a scale without unit"));
+  }
+  Ok(d)
+}
+
+/// Parse and validate the `derived_metrics.json` entries: `{ "<type>": { "<name>": { "expr": "..." } } }`
+fn parse_derived(derived: Map<String, Value>) -> Result<HashMap<String, Vec<DerivedDef>>> {
+  let mut types: HashMap<String, Vec<DerivedDef>> = HashMap::new();
+
+  for (t, block) in derived {
+    let block = match block {
+      Value::Object(b) => b,
+      _ => return Err(anyhow!("{DERIVED_METRICS}: {t} is not an object")),
+    };
+    let defs = types.entry(t.to_lowercase()).or_default();
+
+    for (name, v) in block.into_iter().filter(|(_, v)| *v != Value::Bool(false)) {
+      let d: DerivedEntry = serde_json::from_value(v).map_err(|e| anyhow!("{DERIVED_METRICS}: invalid definition of {name} ({t}): {e}"))?;
+      if !matches!(d.mtype.to_lowercase().as_str(), "counter" | "gauge") {
+        return Err(anyhow!("{DERIVED_METRICS}: {name} ({t}) has an unknown type ({})", d.mtype));
+      }
+      let expr = Expr::parse(&d.expr).map_err(|e| anyhow!("{DERIVED_METRICS}: {name} ({t}): {e}"))?;
+      defs.push(DerivedDef { help: d.help.unwrap_or_else(|| d.expr.clone()),
+                             name,
+                             mtype: d.mtype,
+                             unit: d.unit,
+                             expr });
+    }
+    defs.sort_by(|a, b| a.name.cmp(&b.name));
+  }
+  Ok(types)
+}
+
+/// Parse and validate the `state_definition.json` entries: `{ "<type>": { "<field>": { "name": "...", "values": { "<state>": 0 } } } }`, without `values` the field is a number or a boolean
+fn parse_states(states: Map<String, Value>) -> Result<Vec<StateDef>> {
+  let mut res: Vec<StateDef> = Vec::new();
+
+  for (t, block) in states {
+    let block = match block {
+      Value::Object(b) => b,
+      _ => return Err(anyhow!("{STATE_DEFINITION}: {t} is not an object")),
+    };
+
+    for (field, v) in block.into_iter().filter(|(_, v)| *v != Value::Bool(false)) {
+      let d: StateEntry = serde_json::from_value(v).map_err(|e| anyhow!("{STATE_DEFINITION}: invalid definition of {field} ({t}): {e}"))?;
+      super::utils::valid_json_path(&field).map_err(|e| anyhow!("{STATE_DEFINITION}: {field} ({t}): {e}"))?;
+      if d.values.is_empty() && d.unknown.is_some() {
+        return Err(anyhow!("{STATE_DEFINITION}: {field} ({t}) has an 'unknown' value but no values"));
+      }
+      if !d.values.is_empty() && d.scale.is_some() {
+        return Err(anyhow!("{STATE_DEFINITION}: {field} ({t}) has a 'scale' but its values are states"));
+      }
+
+      let mut values: Vec<(String, f64)> = d.values.into_iter().collect();
+      values.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+      let help = match d.help {
+        Some(h) => h,
+        None if values.is_empty() => field.to_string(),
+        None => format!("{field}: {}", values.iter().map(|(s, v)| format!("{s}={v}")).collect::<Vec<_>>().join(" or ")),
+      };
+      res.push(StateDef { otype: t.to_lowercase(),
+                          prefix: d.prefix.unwrap_or_else(|| t.to_lowercase()),
+                          field,
+                          name: d.name,
+                          help,
+                          values,
+                          unknown: d.unknown,
+                          scale: d.scale });
+    }
+  }
+  res.sort_by(|a, b| a.otype.cmp(&b.otype).then_with(|| a.field.cmp(&b.field)));
+  Ok(res)
+}
+
+/// Parse and validate the `info_definition.json` entries: `{ "<type>": { "<label>": "<field>" } }`
+fn parse_infos(infos: Map<String, Value>) -> Result<InfoDefs> {
+  let mut res: InfoDefs = HashMap::new();
+
+  for (t, block) in infos {
+    let block = match block {
+      Value::Object(b) => b,
+      _ => return Err(anyhow!("{INFO_DEFINITION}: {t} is not an object")),
+    };
+    let labels = res.entry(t.to_lowercase()).or_default();
+
+    for (label, v) in block.into_iter().filter(|(_, v)| *v != Value::Bool(false)) {
+      let path = v.as_str().ok_or_else(|| anyhow!("{INFO_DEFINITION}: {label} ({t}) is not a string"))?;
+      if !valid_label_name(&label) || super::client::BUILTIN_LABELS.contains(&label.as_str()) {
+        return Err(anyhow!("{INFO_DEFINITION}: {label} ({t}) is not a valid label name or collides with a built-in label"));
+      }
+      super::utils::valid_json_path(path).map_err(|e| anyhow!("{INFO_DEFINITION}: {label} ({t}): {e}"))?;
+      labels.push((label, path.to_string()));
+    }
+    labels.sort();
+  }
+  res.retain(|_, l| !l.is_empty());
+  Ok(res)
+}
+
+/// Prometheus metric name rule: [a-zA-Z_:][a-zA-Z0-9_:]*
+pub fn valid_metric_name(name: &str) -> bool {
+  let mut chars = name.chars();
+  match chars.next() {
+    Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':'),
+    _ => false,
+  }
+}
+
+/// Prometheus label name rule: [a-zA-Z_][a-zA-Z0-9_]* (`__` prefix reserved)
+pub fn valid_label_name(name: &str) -> bool {
+  let mut chars = name.chars();
+  match chars.next() {
+    Some(c) if c.is_ascii_alphabetic() || c == '_' => !name.starts_with("__") && chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+    _ => false,
+  }
+}
+
+/// Last modification time of the definition files, used to detect changes
+pub fn modified(source: &Source) -> Vec<Option<SystemTime>> {
+  match &source.cfg_path {
+    None => Vec::new(),
+    Some(cfg_path) => [METRIC_DEFINITION, METRIC_QUERY_SELECTION, DERIVED_METRICS, STATE_DEFINITION, INFO_DEFINITION].iter().map(|f| Path::new(cfg_path).join(f).metadata().and_then(|m| m.modified()).ok()).collect(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::sio::metrics::{builtin_names, Options};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn obj(v: Value) -> Map<String, Value> { v.as_object().unwrap().clone() }
+
+  fn metrics(v: Value) -> Result<(HashMap<String, MetricDef>, TypeDefs)> { parse_metrics(obj(v)) }
+
+  /// Configuration path in the temp dir with the given definition files, unique per test
+  fn cfg_dir(name: &str, files: &[(&str, Value)]) -> String {
+    let d = std::env::temp_dir().join(format!("sio2prom-{}-{name}", std::process::id()));
+    std::fs::create_dir_all(&d).unwrap();
+    for (f, v) in files {
+      std::fs::write(d.join(f), v.to_string()).unwrap();
+    }
+    d.to_string_lossy().to_string()
+  }
+
+  fn gauge(name: &str) -> Value { json!({ "type": "gauge", "help": name, "name": name }) }
+
+  #[test]
+  fn valid_names() {
+    for n in ["sds_read", "_x", "a:b", "Volume_1"] {
+      assert!(valid_metric_name(n), "{n}");
+    }
+    for n in ["", "1a", "a-b", "a b", "é"] {
+      assert!(!valid_metric_name(n), "{n}");
+    }
+    for n in ["vol_name", "_x", "Team1"] {
+      assert!(valid_label_name(n), "{n}");
+    }
+    for n in ["", "__name", "a:b", "1a", "a-b"] {
+      assert!(!valid_label_name(n), "{n}");
+    }
+  }
+
+  #[test]
+  fn type_block_precedence() {
+    let (metrics, types) = metrics(json!({ "read": gauge("read"), "Volume": { "read": gauge("vol_read") }, "Sdc": { "read": false } })).unwrap();
+    let defs = Definitions { metrics, types, ..Default::default() };
+    assert_eq!(defs.metric("Volume", "read").unwrap().unwrap().name, "vol_read");
+    assert_eq!(defs.metric("Sds", "read").unwrap().unwrap().name, "read");
+    assert!(defs.metric("Sdc", "read").unwrap().is_none());
+    assert!(defs.metric("Sds", "write").is_none());
+  }
+
+  #[test]
+  fn overlay_metrics_merge() {
+    let mut base = obj(json!({ "read": gauge("read"), "write": gauge("write"), "Sds": { "read": gauge("sds_read") } }));
+    overlay_metrics(&mut base, obj(json!({ "read": gauge("reads"), "Sds": { "write": false }, "Volume": { "read": gauge("vol_read") } })));
+    assert_eq!(base["read"]["name"], "reads");
+    assert_eq!(base["write"]["name"], "write");
+    assert_eq!(base["Sds"], json!({ "read": gauge("sds_read"), "write": false }));
+    assert_eq!(base["Volume"], json!({ "read": gauge("vol_read") }));
+  }
+
+  #[test]
+  fn overlay_query_merge() {
+    let mut query = obj(json!({ "selectedStatisticsList": [{ "type": "Sds", "allIds": "", "properties": ["a", "b"] }, { "type": "Volume", "ids": ["v1"], "properties": ["a"] }] }));
+    overlay_query(&mut query, obj(json!({ "selectedStatisticsList": [{ "type": "Sds", "ids": ["s1"], "properties": ["b", "c"] }, { "type": "Volume", "allIds": "" }, { "type": "Sdc", "allIds": "", "properties": ["d"] }] }))).unwrap();
+    assert_eq!(query["selectedStatisticsList"],
+               json!([{ "type": "Sds", "ids": ["s1"], "properties": ["a", "b", "c"] }, { "type": "Volume", "allIds": "", "properties": ["a"] }, { "type": "Sdc", "allIds": "", "properties": ["d"] }]));
+
+    assert!(overlay_query(&mut query, obj(json!({ "selectedStatistics": [] }))).is_err());
+    assert!(overlay_query(&mut query, obj(json!({ "selectedStatisticsList": [{ "allIds": "" }] }))).is_err());
+  }
+
+  #[test]
+  fn overlay_blocks_removal() {
+    let mut base = obj(json!({ "Sds": { "a": 1, "b": 2 }, "Sdc": { "c": 3 } }));
+    overlay_blocks(&mut base, obj(json!({ "Sds": { "a": false, "d": 4 }, "Volume": { "e": 5 } })));
+    assert_eq!(Value::Object(base), json!({ "Sds": { "b": 2, "d": 4 }, "Sdc": { "c": 3 }, "Volume": { "e": 5 } }));
+  }
+
+  #[test]
+  fn overlay_and_override() {
+    let path = cfg_dir("overlay", &[(METRIC_DEFINITION, json!({ "numOfDevices": gauge("devices"), "Sds": { "numOfDevices": false } })), (METRIC_QUERY_SELECTION, json!({ "selectedStatisticsList": [{ "type": "Sds", "allIds": "", "properties": ["numOfDevices"] }] })), (INFO_DEFINITION, json!({ "Sds": { "port": false } }))]);
+    let source = |overlay| Source { profile:  DEFAULT_PROFILE.to_string(),
+                                    cfg_path: Some(path.clone()),
+                                    overlay };
+    let profile = Definitions::load(&Source { profile:  DEFAULT_PROFILE.to_string(),
+                                              cfg_path: None,
+                                              overlay:  false, }).unwrap();
+
+    let overlay = Definitions::load(&source(true)).unwrap();
+    assert!(overlay.metric("Sds", "numOfDevices").unwrap().is_none());
+    assert_eq!(overlay.metric("StoragePool", "numOfDevices").unwrap().unwrap().name, "devices");
+    assert_eq!(overlay.metrics.len(), profile.metrics.len());
+    assert_eq!(overlay.selection(), profile.selection());
+    assert!(!overlay.infos["sds"].iter().any(|(l, _)| l == "port"));
+    assert_eq!(overlay.states.len(), profile.states.len());
+
+    let replaced = Definitions::load(&source(false)).unwrap();
+    assert_eq!(replaced.metrics.keys().collect::<Vec<_>>(), vec!["numOfDevices"]);
+    assert_eq!(replaced.selection(), vec![("Sds", vec!["numOfDevices"])]);
+    assert!(!replaced.infos.contains_key("sds"));
+    assert_eq!(replaced.states.len(), profile.states.len());
+    std::fs::remove_dir_all(path).unwrap();
+  }
+
+  #[test]
+  fn parse_errors() {
+    for m in [json!({ "a": { "type": "gauge", "help": "", "name": "" } }), json!({ "a": { "type": "summary", "help": "", "name": "a" } }), json!({ "a": { "type": "gauge", "help": "", "name": "a", "scale": 2 } }), json!({ "a": { "type": "gauge", "name": "a" } })] {
+      assert!(metrics(m.clone()).is_err(), "{m}");
+    }
+    for d in [json!({ "Sds": [] }), json!({ "Sds": { "a": { "type": "summary", "expr": "b" } } }), json!({ "Sds": { "a": { "type": "gauge", "expr": "b +" } } }), json!({ "Sds": { "a": { "type": "gauge" } } })] {
+      assert!(parse_derived(obj(d.clone())).is_err(), "{d}");
+    }
+    for s in [json!({ "Sds": { "a": { "name": "a", "unknown": 1 } } }), json!({ "Sds": { "a": { "name": "a", "values": { "x": 1 }, "scale": 2 } } }), json!({ "Sds": { "a..b": { "name": "a" } } }), json!({ "Sds": { "a": { "name": "a", "other": 1 } } }), json!({ "Sds": 1 })] {
+      assert!(parse_states(obj(s.clone())).is_err(), "{s}");
+    }
+    for i in [json!({ "Sds": { "port": 1 } }), json!({ "Sds": { "sds_name": "name" } }), json!({ "Sds": { "a-b": "name" } }), json!({ "Sds": { "port": "a[x]" } }), json!({ "Sds": "port" })] {
+      assert!(parse_infos(obj(i.clone())).is_err(), "{i}");
+    }
+    assert!(parse_infos(obj(json!({ "Sds": { "port": false } }))).unwrap().is_empty());
+  }
+
+  #[test]
+  fn check_rejections() {
+    let (metrics, types) = metrics(json!({ "a": gauge("x"), "b": gauge("x"), "c": gauge("bad-name"), "Sdc": { "a": gauge("y") } })).unwrap();
+    let defs = Definitions { metrics,
+                             types,
+                             query: obj(json!({ "selectedStatisticsList": [{ "type": "Sds", "allIds": "", "properties": ["a", "b", "a", "missing"] }, { "type": "Sdc", "allIds": "", "properties": ["a"] }] })),
+                             derived: parse_derived(obj(json!({ "Sds": { "z": { "type": "gauge", "expr": "a + d" } } }))).unwrap(),
+                             states: parse_states(obj(json!({ "Sds": { "s": { "name": "x" }, "n": { "name": "network_connected" } } }))).unwrap(),
+                             ..Default::default() };
+    let problems = defs.check(&Options::default());
+    for p in ["metric_definition.json: c has an invalid metric name (bad-name)",
+              "b (Sds) generates the metric sds_x already generated by a (Sds)",
+              "metric_query_selection.json: a (Sds) is selected more than once",
+              "metric_query_selection.json: missing (Sds) not found in (metric_definition.json)",
+              "derived_metrics.json: z (sds) uses d which is not selected in (metric_query_selection.json)",
+              "state_definition.json: s (sds) generates the metric sds_x already generated by a (Sds)",
+              "state_definition.json: n (sds) generates the metric sds_network_connected already generated by the collector"]
+    {
+      assert!(problems.contains(&p.to_string()), "{p} not in {problems:?}");
+    }
+    assert_eq!(problems.len(), 7, "{problems:?}");
+  }
+
+  #[test]
+  fn parse_metrics_disabled() {
+    let (_, types) = metrics(json!({ "Sds": { "a": false, "b": { "disabled": true }, "c": { "name": "c", "type": "Gauge", "help": "c", "disabled": false } } })).unwrap();
+    let sds = &types["sds"];
+    assert!(sds["a"].is_none());
+    assert!(sds["b"].is_none());
+    assert_eq!(sds["c"].as_ref().unwrap().name, "c");
+    assert!(metrics(json!({ "Sds": { "c": { "name": "c", "type": "Gauge", "help": "c", "unknown": 1 } } })).is_err());
+  }
+
+  #[test]
+  fn check_fq_names() {
+    let defs = Definitions::load(&Source { profile:  DEFAULT_PROFILE.to_string(),
+                                           cfg_path: None,
+                                           overlay:  false, }).unwrap();
+    let opts = Options { namespace: Some("sio".to_string()),
+                         subsystems: HashMap::from([("sds".to_string(), "storage".to_string())]),
+                         ..Default::default() };
+    assert!(defs.check(&opts).is_empty());
+    assert!(builtin_names(&opts).contains(&"sio_storage_sds_network_connected".to_string()));
+    assert!(builtin_names(&opts).contains(&"sio_volume_sdc_mapping".to_string()));
+  }
+
+  #[test]
+  fn label_problems() {
+    let defs = Definitions::load(&Source { profile:  DEFAULT_PROFILE.to_string(),
+                                           cfg_path: None,
+                                           overlay:  false, }).unwrap();
+    let opts = Options { labels: BTreeMap::from([("media_type".to_string(), "ssd".to_string())]),
+                         ..Default::default() };
+    let problems = defs.label_problems(&opts);
+    assert!(problems.iter().any(|p| p.contains("media_type (storagepool)")), "{problems:?}");
+    assert!(problems.iter().all(|p| defs.check(&opts).contains(p)));
+    assert!(defs.label_problems(&Options::default()).is_empty());
+  }
+
+  #[test]
+  fn parse_states_prefix() {
+    let states = parse_states(json!({ "System": { "mdmCluster.clusterState": { "prefix": "mdm", "name": "cluster_state", "values": { "ClusteredNormal": 0 } }, "capacityInUseInKb": { "name": "capacity_in_use" } } }).as_object().unwrap().clone()).unwrap();
+    assert_eq!((states[0].field.as_str(), states[0].prefix.as_str(), states[0].otype.as_str()), ("capacityInUseInKb", "system", "system"));
+    assert_eq!((states[1].field.as_str(), states[1].prefix.as_str(), states[1].otype.as_str()), ("mdmCluster.clusterState", "mdm", "system"));
+  }
+}
@@ -0,0 +1,211 @@
+//! Arithmetic expressions used by the derived metrics (`derived_metrics.json`)
+
+use anyhow::{anyhow, Result};
+use std::{fmt, iter::Peekable, str::Chars};
+
+/// Parsed expression over the properties of an object (`a / b`, `(a.x + b.x) * 100`...)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+  Num(f64),
+  Var(String),
+  Neg(Box<Expr>),
+  Bin(char, Box<Expr>, Box<Expr>),
+}
+impl fmt::Display for Expr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Expr::Num(n) => write!(f, "{n}"),
+      Expr::Var(v) => write!(f, "{v}"),
+      Expr::Neg(e) => write!(f, "-{e}"),
+      Expr::Bin(op, l, r) => write!(f, "({l} {op} {r})"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Num(f64),
+  Var(String),
+  Op(char),
+}
+
+impl Expr {
+  pub fn parse(s: &str) -> Result<Expr> {
+    let tokens = tokenize(s)?;
+    let mut pos = 0;
+    let e = parse_sum(&tokens, &mut pos)?;
+    if pos != tokens.len() {
+      return Err(anyhow!("Unexpected {:?} in expression: {}", tokens[pos], s));
+    }
+    Ok(e)
+  }
+
+  /// Property names used by the expression
+  pub fn vars(&self) -> Vec<&str> {
+    match self {
+      Expr::Num(_) => Vec::new(),
+      Expr::Var(v) => vec![v.as_str()],
+      Expr::Neg(e) => e.vars(),
+      Expr::Bin(_, l, r) => {
+        let mut v = l.vars();
+        v.append(&mut r.vars());
+        v
+      },
+    }
+  }
+
+  /// Evaluate the expression, `None` if a property is missing
+  ///
+  /// Divisions follow `div_oper`: a zero numerator or divisor gives 0
+  pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<f64>) -> Option<f64> {
+    match self {
+      Expr::Num(n) => Some(*n),
+      Expr::Var(v) => lookup(v),
+      Expr::Neg(e) => e.eval(lookup).map(|v| -v),
+      Expr::Bin(op, l, r) => {
+        let (l, r) = (l.eval(lookup)?, r.eval(lookup)?);
+        match op {
+          '+' => Some(l + r),
+          '-' => Some(l - r),
+          '*' => Some(l * r),
+          '/' if l == 0.0 || r == 0.0 => Some(0.0),
+          '/' => Some(l / r),
+          _ => None,
+        }
+      },
+    }
+  }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+  let mut tokens: Vec<Token> = Vec::new();
+  let mut chars: Peekable<Chars> = s.chars().peekable();
+
+  while let Some(&c) = chars.peek() {
+    if c.is_whitespace() {
+      chars.next();
+    } else if "+-*/()".contains(c) {
+      tokens.push(Token::Op(c));
+      chars.next();
+    } else if c.is_ascii_digit() || c == '.' {
+      let mut n = String::new();
+      while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+        n.push(c);
+        chars.next();
+      }
+      tokens.push(Token::Num(n.parse::<f64>().map_err(|_| anyhow!("Invalid number {n} in expression: {s}"))?));
+    } else if c.is_ascii_alphabetic() || c == '_' {
+      let mut v = String::new();
+      while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_' || **c == '.') {
+        v.push(c);
+        chars.next();
+      }
+      tokens.push(Token::Var(v));
+    } else {
+      return Err(anyhow!("Invalid character '{c}' in expression: {s}"));
+    }
+  }
+  Ok(tokens)
+}
+
+/// sum := product (('+' | '-') product)*
+fn parse_sum(tokens: &[Token], pos: &mut usize) -> Result<Expr> {
+  let mut e = parse_product(tokens, pos)?;
+  while let Some(Token::Op(op)) = tokens.get(*pos).filter(|t| matches!(t, Token::Op('+') | Token::Op('-'))) {
+    *pos += 1;
+    e = Expr::Bin(*op, Box::new(e), Box::new(parse_product(tokens, pos)?));
+  }
+  Ok(e)
+}
+
+/// product := unary (('*' | '/') unary)*
+fn parse_product(tokens: &[Token], pos: &mut usize) -> Result<Expr> {
+  let mut e = parse_unary(tokens, pos)?;
+  while let Some(Token::Op(op)) = tokens.get(*pos).filter(|t| matches!(t, Token::Op('*') | Token::Op('/'))) {
+    *pos += 1;
+    e = Expr::Bin(*op, Box::new(e), Box::new(parse_unary(tokens, pos)?));
+  }
+  Ok(e)
+}
+
+/// unary := '-' unary | number | property | '(' sum ')'
+fn parse_unary(tokens: &[Token], pos: &mut usize) -> Result<Expr> {
+  let t = tokens.get(*pos).ok_or_else(|| anyhow!("Unexpected end of expression"))?;
+  *pos += 1;
+  match t {
+    Token::Op('-') => Ok(Expr::Neg(Box::new(parse_unary(tokens, pos)?))),
+    Token::Num(n) => Ok(Expr::Num(*n)),
+    Token::Var(v) => Ok(Expr::Var(v.to_string())),
+    Token::Op('(') => {
+      let e = parse_sum(tokens, pos)?;
+      match tokens.get(*pos) {
+        Some(Token::Op(')')) => {
+          *pos += 1;
+          Ok(e)
+        },
+        _ => Err(anyhow!("Missing ')' in expression")),
+      }
+    },
+    t => Err(anyhow!("Unexpected {:?} in expression", t)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn eval(s: &str) -> Option<f64> {
+    let vars = |v: &str| match v {
+      "a" => Some(6.0),
+      "b" => Some(3.0),
+      "zero" => Some(0.0),
+      "c.x" => Some(2.0),
+      _ => None,
+    };
+    Expr::parse(s).unwrap().eval(&vars)
+  }
+
+  #[test]
+  fn precedence() {
+    assert_eq!(eval("1 + 2 * 3"), Some(7.0));
+    assert_eq!(eval("a - b - 1"), Some(2.0));
+    assert_eq!(eval("a / b * 2"), Some(4.0));
+    assert_eq!(Expr::parse("a + b * c.x").unwrap().to_string(), "(a + (b * c.x))");
+  }
+
+  #[test]
+  fn parentheses() {
+    assert_eq!(eval("(1 + 2) * 3"), Some(9.0));
+    assert_eq!(eval("((a + b)) / (b - 2)"), Some(9.0));
+    assert_eq!(eval("(a + c.x) * 100"), Some(800.0));
+  }
+
+  #[test]
+  fn unary_minus() {
+    assert_eq!(eval("-a"), Some(-6.0));
+    assert_eq!(eval("--a"), Some(6.0));
+    assert_eq!(eval("b * -2"), Some(-6.0));
+    assert_eq!(eval("-(a - b) + 1"), Some(-2.0));
+  }
+
+  #[test]
+  fn division_by_zero() {
+    assert_eq!(eval("a / zero"), Some(0.0));
+    assert_eq!(eval("a / 0"), Some(0.0));
+    assert_eq!(eval("zero / b"), Some(0.0));
+  }
+
+  #[test]
+  fn unknown_variables() {
+    assert_eq!(eval("a + missing"), None);
+    assert_eq!(eval("missing / 0"), None);
+    assert_eq!(Expr::parse("a / (b + c.x)").unwrap().vars(), vec!["a", "b", "c.x"]);
+  }
+
+  #[test]
+  fn syntax_errors() {
+    for s in ["", "a +", "(a + b", "a + b)", "a b", "a % b", "1..2", "* a"] {
+      assert!(Expr::parse(s).is_err(), "{s} should be rejected");
+    }
+  }
+}
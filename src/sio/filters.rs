@@ -0,0 +1,218 @@
+//! Object include / exclude filters per object type (`[filters.<type>]` of the configuration file)
+
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{value::Map, Value};
+use std::collections::{HashMap, HashSet};
+
+/// Object types that can be filtered (`[filters.<type>]`)
+pub const FILTER_TYPES: &[&str] = &["sdc", "sds", "volume", "device", "storagepool", "protectiondomain", "faultset", "sdr", "replicationconsistencygroup", "replicationpair", "peermdm"];
+
+/// Patterns matched against an object, its ID or any of its parents (name or ID)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Patterns {
+  #[serde(deserialize_with = "super::utils::de_regex")]
+  pub name:   Option<Regex>,
+  #[serde(deserialize_with = "super::utils::de_regex")]
+  pub id:     Option<Regex>,
+  #[serde(deserialize_with = "super::utils::de_regex")]
+  pub parent: Option<Regex>,
+}
+
+/// Objects are kept if they match all the `include` patterns and none of the `exclude` ones
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+  pub include: Patterns,
+  pub exclude: Patterns,
+}
+
+impl Patterns {
+  /// Result of each defined pattern
+  fn matches(&self, name: &str, id: &str, parents: &[(&str, &str)]) -> Vec<bool> {
+    let mut res: Vec<bool> = Vec::new();
+    if let Some(r) = &self.name {
+      res.push(r.is_match(name));
+    }
+    if let Some(r) = &self.id {
+      res.push(r.is_match(id));
+    }
+    if let Some(r) = &self.parent {
+      res.push(parents.iter().any(|(n, i)| r.is_match(n) || r.is_match(i)));
+    }
+    res
+  }
+}
+
+impl Filter {
+  pub fn keep(&self, name: &str, id: &str, parents: &[(&str, &str)]) -> bool { self.include.matches(name, id, parents).iter().all(|m| *m) && !self.exclude.matches(name, id, parents).iter().any(|m| *m) }
+}
+
+/// Object type of an instance list (`volumeList` -> `volume`), as used by the relations and labels
+pub fn list_type(list: &str) -> String { list.replace("List", "").to_lowercase() }
+
+/// IDs of the objects removed by the filters
+pub fn excluded(filters: &HashMap<String, Filter>, instances: &Map<String, Value>, relations: &HashMap<&'static str, HashMap<String, HashMap<String, Vec<String>>>>) -> HashSet<String> {
+  let mut excluded: HashSet<String> = HashSet::new();
+  if filters.is_empty() {
+    return excluded;
+  }
+
+  let names: HashMap<&str, &str> = instances.values()
+                                            .filter_map(|v| v.as_array())
+                                            .flatten()
+                                            .filter_map(|o| Some((o.get("id")?.as_str()?, o.get("name").and_then(|n| n.as_str()).unwrap_or_default())))
+                                            .collect();
+
+  for (list, items) in instances.iter().filter_map(|(k, v)| v.as_array().map(|a| (k, a))) {
+    let filter = match filters.get(&list_type(list)) {
+      None => continue,
+      Some(f) => f,
+    };
+
+    for o in items {
+      let id = match o.get("id").and_then(|i| i.as_str()) {
+        None => continue,
+        Some(i) => i,
+      };
+      let name = o.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+      let parents: Vec<(&str, &str)> = ancestors(id, relations).into_iter().map(|p| (names.get(p).copied().unwrap_or_default(), p)).collect();
+
+      if !filter.keep(name, id, &parents) {
+        trace!("Filtered out {} {} ({})", list_type(list), name, id);
+        excluded.insert(id.to_string());
+      }
+    }
+  }
+
+  excluded
+}
+
+/// All the parents of an object up to the System
+fn ancestors<'a>(id: &str, relations: &'a HashMap<&'static str, HashMap<String, HashMap<String, Vec<String>>>>) -> Vec<&'a str> {
+  let mut res: Vec<&str> = Vec::new();
+  let mut pending: Vec<&str> = relations.get("parents").and_then(|p| p.get(id)).map(|p| p.values().flatten().map(|s| s.as_str()).collect()).unwrap_or_default();
+
+  while let Some(p) = pending.pop() {
+    if res.contains(&p) {
+      continue;
+    }
+    res.push(p);
+    if let Some(pp) = relations.get("parents").and_then(|r| r.get(p)) {
+      pending.extend(pp.values().flatten().map(|s| s.as_str()));
+    }
+  }
+  res
+}
+
+/// Restrict the statistics query of the filtered object types to the remaining object IDs (`ids` instead of `allIds`)
+pub fn narrow_query(query: &Map<String, Value>, filters: &HashMap<String, Filter>, instances: &Map<String, Value>, excluded: &HashSet<String>) -> Map<String, Value> {
+  let mut query = query.clone();
+  let list = match query.get_mut("selectedStatisticsList").and_then(|l| l.as_array_mut()) {
+    None => return query,
+    Some(l) => l,
+  };
+
+  list.retain_mut(|s| {
+        let stype = s.get("type").and_then(|t| t.as_str()).unwrap_or_default().to_lowercase();
+        let entry = match s.as_object_mut() {
+          Some(e) if filters.contains_key(&stype) => e,
+          _ => return true,
+        };
+
+        let ids: Vec<Value> = match entry.remove("allIds") {
+          Some(_) => instances.iter().filter(|(k, _)| list_type(k) == stype).filter_map(|(_, v)| v.as_array()).flatten().filter_map(|o| o.get("id")).filter(|i| !excluded.contains(i.as_str().unwrap_or_default())).cloned().collect(),
+          None => entry.get("ids").and_then(|i| i.as_array()).into_iter().flatten().filter(|i| !excluded.contains(i.as_str().unwrap_or_default())).cloned().collect(),
+        };
+        if ids.is_empty() {
+          debug!("All the {} objects are filtered out, removed from the statistics query", stype);
+          return false;
+        }
+        entry.insert("ids".to_string(), Value::Array(ids));
+        true
+      });
+
+  query
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn filter(toml: &str) -> Filter { toml::from_str(toml).unwrap() }
+
+  fn map(v: Value) -> Map<String, Value> { v.as_object().unwrap().clone() }
+
+  /// System s1 > ProtectionDomain pd1 > StoragePool sp1 > Volumes v1 (data1), v2 (data2), v3 (logs)
+  fn instances() -> Map<String, Value> {
+    map(json!({ "protectionDomainList": [{ "id": "pd1", "name": "pd" }],
+                "storagePoolList": [{ "id": "sp1", "name": "ssd" }],
+                "volumeList": [{ "id": "v1", "name": "data1" }, { "id": "v2", "name": "data2" }, { "id": "v3", "name": "logs" }] }))
+  }
+
+  fn relations() -> HashMap<&'static str, HashMap<String, HashMap<String, Vec<String>>>> {
+    let mut parents: HashMap<String, HashMap<String, Vec<String>>> = HashMap::new();
+    parents.insert("pd1".to_string(), HashMap::from([("system".to_string(), vec!["s1".to_string()])]));
+    parents.insert("sp1".to_string(), HashMap::from([("protectiondomain".to_string(), vec!["pd1".to_string()])]));
+    for v in ["v1", "v2", "v3"] {
+      parents.insert(v.to_string(), HashMap::from([("storagepool".to_string(), vec!["sp1".to_string()])]));
+    }
+    HashMap::from([("parents", parents)])
+  }
+
+  #[test]
+  fn include_exclude() {
+    let parents = [("ssd", "sp1")];
+    assert!(Filter::default().keep("data1", "v1", &parents));
+
+    let f = filter("include.name = '^data'");
+    assert!(f.keep("data1", "v1", &parents));
+    assert!(!f.keep("logs", "v3", &parents));
+
+    let f = filter("exclude.id = '^v2$'");
+    assert!(f.keep("data1", "v1", &parents));
+    assert!(!f.keep("data2", "v2", &parents));
+
+    let f = filter("include.parent = '^ssd$'");
+    assert!(f.keep("data1", "v1", &parents));
+    assert!(!f.keep("data1", "v1", &[("hdd", "sp2")]));
+    let f = filter("exclude.parent = '^sp1$'");
+    assert!(!f.keep("data1", "v1", &parents));
+
+    let f = filter("include.name = '^data'\nexclude.name = '2$'");
+    assert!(f.keep("data1", "v1", &parents));
+    assert!(!f.keep("data2", "v2", &parents));
+    assert!(!f.keep("logs", "v3", &parents));
+  }
+
+  #[test]
+  fn ancestor_walk() {
+    let relations = relations();
+    let mut res = ancestors("v1", &relations);
+    res.sort();
+    assert_eq!(res, vec!["pd1", "s1", "sp1"]);
+    assert!(ancestors("s1", &relations).is_empty());
+
+    // Filter on a grand parent name
+    let filters = HashMap::from([("volume".to_string(), filter("exclude.parent = '^pd$'"))]);
+    assert_eq!(excluded(&filters, &instances(), &relations), HashSet::from(["v1".to_string(), "v2".to_string(), "v3".to_string()]));
+  }
+
+  #[test]
+  fn narrow() {
+    let query = map(json!({ "selectedStatisticsList": [{ "type": "Volume", "allIds": [], "properties": ["userDataReadBwc"] },
+                                                        { "type": "StoragePool", "allIds": [], "properties": ["capacityInUseInKb"] },
+                                                        { "type": "ProtectionDomain", "ids": ["pd1"], "properties": ["numOfSds"] }] }));
+    let filters = HashMap::from([("volume".to_string(), filter("exclude.name = '^logs$'")), ("protectiondomain".to_string(), filter("exclude.id = '.'"))]);
+    let excl = excluded(&filters, &instances(), &relations());
+    assert_eq!(excl, HashSet::from(["v3".to_string(), "pd1".to_string()]));
+
+    let res = narrow_query(&query, &filters, &instances(), &excl);
+    let list = res["selectedStatisticsList"].as_array().unwrap();
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0], json!({ "type": "Volume", "ids": ["v1", "v2"], "properties": ["userDataReadBwc"] }));
+    assert_eq!(list[1], query["selectedStatisticsList"][1]);
+  }
+}
@@ -1,32 +1,116 @@
 use serde_json::value::Map;
-use std::{collections::HashMap, fmt};
+use std::{
+  collections::{BTreeMap, HashMap},
+  fmt,
+};
+
+use super::{
+  definitions::{Definitions, DerivedDef, MetricDef, StateDef},
+  filters::Filter,
+  rules::LabelRule,
+};
 
 pub struct Metric {
+  /// Object type (lowercase) the metric belongs to, used to select its subsystem
+  pub otype:  String,
   pub name:   String,
   pub mtype:  String,
   pub help:   String,
   pub labels: HashMap<&'static str, String>,
   pub value:  f64,
+  pub unit:   Option<String>,
 }
 impl fmt::Debug for Metric {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}{:?} {} ({})", self.name, self.labels, self.value, self.mtype) }
 }
 impl Metric {
-  pub fn new(name: String, mtype: String, help: String, labels: HashMap<&'static str, String>, value: f64) -> Metric {
-    Metric { name,
+  pub fn new(otype: &str, name: String, mtype: String, help: String, labels: HashMap<&'static str, String>, value: f64) -> Metric {
+    Metric { otype: otype.to_lowercase(),
+             name,
              mtype,
              help,
              labels,
-             value }
+             value,
+             unit: None }
+  }
+
+  pub fn with_unit(mut self, unit: Option<&str>) -> Metric {
+    self.unit = unit.map(|u| u.to_string());
+    self
   }
 }
 
+/// Options applied when building the metrics
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+  /// Keep the legacy unit names (_kb, _us...) instead of converting to base units
+  pub legacy_units:        bool,
+  /// Prefix of all the exported metric names
+  pub namespace:           Option<String>,
+  /// Optional subsystem per object type (lowercase), added after the namespace
+  pub subsystems:          HashMap<String, String>,
+  /// Include / exclude filters per object type (lowercase)
+  pub filters:             HashMap<String, Filter>,
+  /// Constant labels added to all the series of the gateway
+  pub labels:              BTreeMap<String, String>,
+  /// Labels from instance fields per object type (lowercase): label name -> json path
+  pub fields:              HashMap<String, BTreeMap<String, String>>,
+  /// Regex / lookup label rules
+  pub rules:               Vec<LabelRule>,
+  /// Expose the states as StateSets (one series per state with a `state` label) instead of numbers
+  pub state_set:           bool,
+  /// Collect the SDS to SDS network latency meters (one request per SDS)
+  pub sds_network_latency: bool,
+  /// Collect the device latency meters (one request per SDS)
+  pub device_latency:      bool,
+}
+impl Options {
+  /// Subsystem of an object type
+  pub fn subsystem(&self, otype: &str) -> Option<&str> { self.subsystems.get(&otype.to_lowercase()).map(|s| s.as_str()) }
+
+  /// Fully-qualified name of a metric of an object type, as registered (`namespace_subsystem_name`)
+  pub fn fq_name(&self, otype: &str, name: &str) -> String { prometheus::Opts::new(name, "").namespace(self.namespace.as_deref().unwrap_or_default()).subsystem(self.subsystem(otype).unwrap_or_default()).fq_name() }
+}
+
+/// Label names added by the collector to some of its own metrics (MDM members, alerts, SDC mappings, SDS network meters), they can't be redefined by the configuration
+pub const METRIC_LABELS: &[&str] = &["role", "name", "ip", "alert_type", "severity", "affected_object_type", "affected_object_id", "object_name", "access_mode", "peer_sds_name", "peer_sds_id", "peer_ip"];
+
+/// Metrics generated by the collector and not by the definition files: object type, base units name and legacy units name
+const BUILTIN_METRICS: &[(&str, &str, &str)] = &[("volume", "volume_size_bytes", "volume_size_in_kb"),
+                                                 ("volume", "volume_sdc_mapping", "volume_sdc_mapping"),
+                                                 ("volume", "volume_sdc_limit_iops", "volume_sdc_limit_iops"),
+                                                 ("volume", "volume_sdc_limit_bw_bytes", "volume_sdc_limit_bw_in_mbps"),
+                                                 ("volume", "volume_qos_iops_utilisation", "volume_qos_iops_utilisation"),
+                                                 ("volume", "volume_qos_bw_utilisation", "volume_qos_bw_utilisation"),
+                                                 ("sds", "sds_network_latency_avg_seconds", "sds_network_latency_avg_us"),
+                                                 ("sds", "sds_network_latency_max_seconds", "sds_network_latency_max_us"),
+                                                 ("sds", "sds_network_connected", "sds_network_connected"),
+                                                 ("device", "device_latency_read_seconds", "device_latency_read_us"),
+                                                 ("device", "device_latency_write_seconds", "device_latency_write_us"),
+                                                 ("replicationconsistencygroup", "replicationconsistencygroup_rpo_seconds", "replicationconsistencygroup_rpo_seconds"),
+                                                 ("faultset", "faultset_sds_disconnected", "faultset_sds_disconnected"),
+                                                 ("faultset", "faultset_sds_in_maintenance", "faultset_sds_in_maintenance"),
+                                                 ("mdm", "mdm_cluster_members", "mdm_cluster_members"),
+                                                 ("alert", "alert", "alert"),
+                                                 ("alert", "alerts", "alerts")];
+
+/// Legacy unit suffixes replaced by the base unit of the definition
+const UNIT_SUFFIXES: &[&str] = &["_in_kb", "_kb", "inkb", "_in_mb", "_mb", "inmb", "_in_microsec", "_microsec", "inmicrosec", "_ms", "_us", "_in_bytes", "_bytes", "inbytes"];
+
+/// Fully-qualified names of the metrics generated by the collector, checked against the ones of the definition files
+pub fn builtin_names(opts: &Options) -> Vec<String> {
+  BUILTIN_METRICS.iter().map(|(t, n, l)| opts.fq_name(t, if opts.legacy_units { l } else { n })).collect()
+}
+
 /// Merge the States and Perf Metrics
-pub fn get(cfg_path: Option<&str>, inst: &Result<Map<String, serde_json::Value>, anyhow::Error>, stats: &Result<Map<String, serde_json::Value>, anyhow::Error>, labels: &Result<HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>, String>, rela: &Result<HashMap<&'static str, HashMap<String, HashMap<String, Vec<String>>>>, String>) -> Option<Vec<Metric>> {
+pub fn get(defs: &Definitions, opts: &Options, inst: &Result<Map<String, serde_json::Value>, anyhow::Error>, stats: &Result<Map<String, serde_json::Value>, anyhow::Error>, labels: &Result<HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>, String>, alerts: Option<&[serde_json::Value]>) -> Option<Vec<Metric>> {
   let mut metric_list: Vec<Metric> = Vec::new();
 
-  let m = convert_metrics(cfg_path, stats.as_ref().unwrap(), labels.as_ref().unwrap());
-  let s = convert_states(inst.as_ref().unwrap(), rela.as_ref().unwrap());
+  let m = convert_metrics(defs, opts, stats.as_ref().unwrap(), labels.as_ref().unwrap());
+  let s = convert_states(defs, opts, inst.as_ref().unwrap(), labels.as_ref().unwrap());
+  let i = convert_infos(defs, inst.as_ref().unwrap(), labels.as_ref().unwrap());
+  let q = convert_qos(inst.as_ref().unwrap(), stats.as_ref().unwrap(), labels.as_ref().unwrap());
+  let a = alerts.and_then(|a| convert_alerts(a, labels.as_ref().unwrap()));
 
   if let Some(mut value) = m {
     metric_list.append(&mut value);
@@ -34,6 +118,15 @@ pub fn get(cfg_path: Option<&str>, inst: &Result<Map<String, serde_json::Value>,
   if let Some(mut value) = s {
     metric_list.append(&mut value);
   }
+  if let Some(mut value) = i {
+    metric_list.append(&mut value);
+  }
+  if let Some(mut value) = q {
+    metric_list.append(&mut value);
+  }
+  if let Some(mut value) = a {
+    metric_list.append(&mut value);
+  }
 
   if metric_list.is_empty() {
     None
@@ -42,437 +135,357 @@ pub fn get(cfg_path: Option<&str>, inst: &Result<Map<String, serde_json::Value>,
   }
 }
 
-/// Build the metrics from the states
-fn convert_states(instances: &Map<String, serde_json::Value>, relations: &HashMap<&'static str, HashMap<String, HashMap<String, Vec<String>>>>) -> Option<Vec<Metric>> {
-  let default_val = vec![serde_json::Value::Null];
+/// Build the metrics from the states (`state_definition.json`), the objects without labels (filtered) are skipped
+fn convert_states(defs: &Definitions, opts: &Options, instances: &Map<String, serde_json::Value>, labels: &HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>) -> Option<Vec<Metric>> {
   let mut metric_list: Vec<Metric> = Vec::new();
 
-  let clu_id = instances.get("System").and_then(|o| o.as_object().and_then(|j| j.get("id")).map(|s| s.to_string().replace('"', ""))).expect("clu_id Not found");
-  let clu_name = match instances.get("System").and_then(|o| o.as_object().and_then(|j| j.get("name")).map(|s| s.to_string().replace('"', ""))) {
-    None => {
-      warn!("clu_name Not found using clu_id as name");
-      clu_id.to_string()
-    },
-    Some(s) => s,
-  };
-
-  // Sdc
-  for sdc in instances.get("sdcList").and_then(|v| v.as_array()).unwrap_or_else(|| {
-                                                                  error!("Failed to get 'sdcList' from instances");
-                                                                  &default_val
-                                                                })
-  {
-    for sdc in sdc.as_object().iter() {
-      let sdc_name = sdc.get("name").map(|s| s.to_string().replace('"', "")).expect("sdc_name Not found");
-      let sdc_id = sdc.get("id").map(|s| s.to_string().replace('"', "")).expect("sdc_id Not found");
-      let sdc_state_mdm_connection = match sdc.get("mdmConnectionState").map(|s| s.to_string().replace('"', "")) {
-        Some(s) => {
-          match s.as_str() {
-            "Connected" => Some(0.0),
-            "Disconnected" => Some(1.0),
-            _ => {
-              warn!("Unknown mdmConnectionState: {:?}", s);
-              None
-            },
-          }
-        },
-        None => None,
-      };
-
-      if let Some(value) = sdc_state_mdm_connection {
-        let mut label: HashMap<&'static str, String> = HashMap::new();
-        label.entry("clu_name").or_insert_with(|| clu_name.to_string());
-        label.entry("clu_id").or_insert_with(|| clu_id.to_string());
-        label.entry("sdc_name").or_insert_with(|| sdc_name);
-        label.entry("sdc_id").or_insert_with(|| sdc_id.to_string());
-
-        let state: Metric = Metric::new("sdc_state_mdm_connection".to_string(), "gauge".to_string(), "mdmConnectionState: Connected=0.0 or Disconnected=1.0".to_string(), label.clone(), value);
-        metric_list.push(state);
+  for d in defs.states.iter() {
+    for (o, label) in objects(&d.otype, instances, labels) {
+      let state = super::utils::json_path_str(o, &d.field);
+      if !state.is_empty() {
+        state_metric(d, &state, label, opts, &mut metric_list);
       }
     }
   }
 
-  // Sds
-  for sds in instances.get("sdsList").and_then(|v| v.as_array()).unwrap_or_else(|| {
-                                                                  error!("Failed to get 'sdsList' from instances");
-                                                                  &default_val
-                                                                })
-  {
-    let mut parent: HashMap<&'static str, String> = HashMap::new();
-
-    for sds in sds.as_object().iter() {
-      let sds_name = sds.get("name").map(|s| s.to_string().replace('"', "")).expect("sds_name Not found");
-      let sds_id = sds.get("id").map(|s| s.to_string().replace('"', "")).expect("sds_id Not found");
-      let sds_state = match sds.get("sdsState").map(|s| s.to_string().replace('"', "")) {
-        Some(s) => {
-          match s.as_str() {
-            "Normal" => Some(0.0),
-            "RemovePending" => Some(1.0),
-            _ => {
-              warn!("Unknown sdsState: {:?}", s);
-              None
-            },
-          }
-        },
-        None => None,
-      };
-      let sds_state_mdm_connection = match sds.get("mdmConnectionState").map(|s| s.to_string().replace('"', "")) {
-        Some(s) => {
-          match s.as_str() {
-            "Connected" => Some(0.0),
-            "Disconnected" => Some(1.0),
-            _ => {
-              warn!("Unknown mdmConnectionState: {:?}", s);
-              None
-            },
-          }
-        },
-        None => None,
-      };
-      let sds_state_membership = match sds.get("membershipState").map(|s| s.to_string().replace('"', "")) {
-        Some(s) => {
-          match s.as_str() {
-            "Joined" => Some(0.0),
-            "JoinPending" => Some(1.0),
-            "Decoupled" => Some(2.0),
-            _ => {
-              warn!("Unknown membershipState: {:?}", s);
-              None
-            },
-          }
-        },
-        None => None,
-      };
-      let sds_state_maintenance = match sds.get("maintenanceState").map(|s| s.to_string().replace('"', "")) {
-        Some(s) => {
-          match s.as_str() {
-            "NoMaintenance" => Some(0.0),
-            "InMaintenance" => Some(1.0),
-            "SetMaintenanceInProgress" => Some(2.0),
-            "ExitMaintenanceInProgress" => Some(3.0),
-            _ => {
-              warn!("Unknown maintenanceState: {:?}", s);
-              None
-            },
-          }
-        },
-        None => None,
+  // MDM cluster members
+  if let Some((system, label)) = objects("system", instances, labels).into_iter().next() {
+    mdm_members(defs, system, label, opts, &mut metric_list);
+  }
+
+  // Volumes
+  for (vol, label) in objects("volume", instances, labels) {
+    let size_in_kb: Option<f64> = match vol.get("sizeInKb").map(|s| s.to_string().replace('"', "")) {
+      Some(s) => s.parse::<f64>().ok(),
+      None => None,
+    };
+
+    if let Some(value) = size_in_kb {
+      let state: Metric = if opts.legacy_units {
+        Metric::new("volume", "volume_size_in_kb".to_string(), "gauge".to_string(), "volume_size_in_kb".to_string(), label.clone(), value)
+      } else {
+        Metric::new("volume", "volume_size_bytes".to_string(), "gauge".to_string(), "volume_size_bytes".to_string(), label.clone(), value * 1024.0).with_unit(Some("bytes"))
       };
+      metric_list.push(state);
+    }
 
-      for pd in instances["protectionDomainList"].as_array().unwrap().iter() {
-        for pdo in pd.as_object().iter() {
-          if relations["parents"][&sds_id]["protectiondomain"].contains(&pdo["id"].to_string().replace('"', "")) {
-            parent.entry("name").or_insert_with(|| pdo["name"].to_string().replace('"', ""));
-            parent.entry("id").or_insert_with(|| pdo["id"].to_string().replace('"', ""));
-            break;
-          }
-        }
-      }
+    // SDC mappings with their limits (0: unlimited)
+    for map in vol.get("mappedSdcInfo").and_then(|v| v.as_array()).into_iter().flatten() {
+      let l = mapping_labels(map, label, labels);
+      metric_list.push(Metric::new("volume", "volume_sdc_mapping".to_string(), "gauge".to_string(), "Volume mapped to an SDC".to_string(), l.clone(), 1.0));
 
-      let mut label: HashMap<&'static str, String> = HashMap::new();
-      label.entry("clu_name").or_insert_with(|| clu_name.to_string());
-      label.entry("clu_id").or_insert_with(|| clu_id.to_string());
-      label.entry("sds_name").or_insert_with(|| sds_name.clone());
-      label.entry("sds_id").or_insert_with(|| sds_id.to_string());
-      label.entry("pdo_name").or_insert_with(|| parent["name"].to_string());
-      label.entry("pdo_id").or_insert_with(|| parent["id"].to_string());
-
-      if let Some(value) = sds_state {
-        let state: Metric = Metric::new("sds_state".to_string(), "gauge".to_string(), "sdsState: Normal=0.0 or RemovePending=1.0".to_string(), label.clone(), value);
-        metric_list.push(state);
-      }
-      if let Some(value) = sds_state_mdm_connection {
-        let state: Metric = Metric::new("sds_state_mdm_connection".to_string(), "gauge".to_string(), "mdmConnectionState: Connected=0.0 or Disconnected=1.0".to_string(), label.clone(), value);
-        metric_list.push(state);
+      if let Some(value) = map.get("limitIops").and_then(|v| v.as_f64()) {
+        metric_list.push(Metric::new("volume", "volume_sdc_limit_iops".to_string(), "gauge".to_string(), "IOPS limit of the volume on the SDC (0: unlimited)".to_string(), l.clone(), value));
       }
-      if let Some(value) = sds_state_membership {
-        let state: Metric = Metric::new("sds_state_membership".to_string(), "gauge".to_string(), "membershipState: Joined=0.0 or JoinPending=1.0 or Decoupled=2.0".to_string(), label.clone(), value);
-        metric_list.push(state);
-      }
-      if let Some(value) = sds_state_maintenance {
-        let state: Metric = Metric::new("sds_state_maintenance".to_string(), "gauge".to_string(), "maintenanceState: NoMaintenance=0.0 or InMaintenance=1.0 or SetMaintenanceInProgress=2.0 or ExitMaintenanceInProgress=3.0".to_string(), label.clone(), value);
-        metric_list.push(state);
+      if let Some(value) = map.get("limitBwInMbps").and_then(|v| v.as_f64()) {
+        let limit: Metric = if opts.legacy_units {
+          Metric::new("volume", "volume_sdc_limit_bw_in_mbps".to_string(), "gauge".to_string(), "Bandwidth limit of the volume on the SDC in MB/s (0: unlimited)".to_string(), l.clone(), value)
+        } else {
+          Metric::new("volume", "volume_sdc_limit_bw_bytes".to_string(), "gauge".to_string(), "Bandwidth limit of the volume on the SDC in bytes/s (0: unlimited)".to_string(), l.clone(), value * 1024.0 * 1024.0).with_unit(Some("bytes"))
+        };
+        metric_list.push(limit);
       }
     }
   }
 
-  // Volumes
-  for vl in instances.get("volumeList").and_then(|v| v.as_array()).unwrap_or_else(|| {
-                                                                    error!("Failed to get 'volumeList' from instances");
-                                                                    &default_val
-                                                                  })
-  {
-    let mut parent_sto: HashMap<&'static str, String> = HashMap::new();
-    let mut parent_pdo: HashMap<&'static str, String> = HashMap::new();
-
-    for vol in vl.as_object().iter() {
-      let mut label: HashMap<&'static str, String> = HashMap::new();
-      let vol_name = vol.get("name").map(|s| s.to_string().replace('"', "")).expect("vol_name Not found");
-      let vol_id = vol.get("id").map(|s| s.to_string().replace('"', "")).expect("vol_id Not found");
-      let vol_type = vol.get("volumeType").map(|s| s.to_string().replace('"', "")).expect("vol_type Not found");
-
-      for sp in instances["storagePoolList"].as_array().unwrap().iter() {
-        for sto in sp.as_object().iter() {
-          if relations["parents"][&vol_id]["storagepool"].contains(&sto["id"].to_string().replace('"', "")) {
-            parent_sto.entry("name").or_insert_with(|| sto["name"].to_string().replace('"', ""));
-            parent_sto.entry("id").or_insert_with(|| sto["id"].to_string().replace('"', ""));
-            break;
-          }
+  // SDS network latency meters per peer SDS (opt-in, see `ClientInfo::meters`)
+  for (sds, label) in objects("sds", instances, labels) {
+    for m in sds.get("querySdsNetworkLatencyMeters").and_then(|v| v.as_array()).into_iter().flatten() {
+      let peer_id = super::utils::json_path_str(m, "sdsId");
+      let mut l = label.clone();
+      l.insert("peer_sds_name", labels.get("sds").and_then(|s| s.get(&peer_id)).and_then(|s| s.get("sds_name")).cloned().unwrap_or_default());
+      l.insert("peer_sds_id", peer_id);
+      l.insert("peer_ip", super::utils::json_path_str(m, "ipAddress"));
+
+      for (field, name, desc) in [("averageLatencyInMicroSec", "avg", "Average"), ("maxLatencyInMicroSec", "max", "Maximum")] {
+        if let Some(value) = m.get(field).and_then(|v| v.as_f64()) {
+          let latency: Metric = if opts.legacy_units {
+            Metric::new("sds", format!("sds_network_latency_{name}_us"), "gauge".to_string(), format!("{desc} network latency to the peer SDS in microseconds"), l.clone(), value)
+          } else {
+            Metric::new("sds", format!("sds_network_latency_{name}_seconds"), "gauge".to_string(), format!("{desc} network latency to the peer SDS in seconds"), l.clone(), value / 1_000_000.0).with_unit(Some("seconds"))
+          };
+          metric_list.push(latency);
         }
       }
-      for pd in instances["protectionDomainList"].as_array().unwrap().iter() {
-        for pdo in pd.as_object().iter() {
-          if relations["parents"][&parent_sto["id"].to_string().replace('"', "")]["protectiondomain"].contains(&pdo["id"].to_string().replace('"', "")) {
-            parent_pdo.entry("name").or_insert_with(|| pdo["name"].to_string().replace('"', ""));
-            parent_pdo.entry("id").or_insert_with(|| pdo["id"].to_string().replace('"', ""));
-            break;
-          }
-        }
+      if let Some(connected) = m.get("connected").and_then(|v| v.as_bool()) {
+        metric_list.push(Metric::new("sds", "sds_network_connected".to_string(), "gauge".to_string(), "Connectivity to the peer SDS IP".to_string(), l, f64::from(u8::from(connected))));
       }
+    }
+  }
 
-      label.entry("clu_name").or_insert_with(|| clu_name.to_string());
-      label.entry("clu_id").or_insert_with(|| clu_id.to_string());
-      label.entry("vol_name").or_insert_with(|| vol_name);
-      label.entry("vol_id").or_insert_with(|| vol_id.to_string());
-      label.entry("vol_type").or_insert_with(|| vol_type.to_string());
-      label.entry("sto_name").or_insert_with(|| parent_sto["name"].to_string());
-      label.entry("sto_id").or_insert_with(|| parent_sto["id"].to_string());
-      label.entry("pdo_name").or_insert_with(|| parent_pdo["name"].to_string());
-      label.entry("pdo_id").or_insert_with(|| parent_pdo["id"].to_string());
-
-      let size_in_kb: Option<f64> = match vol.get("sizeInKb").map(|s| s.to_string().replace('"', "")) {
-        Some(s) => s.parse::<f64>().ok(),
-        None => None,
+  // Device latency meters, collected per SDS (opt-in, see `ClientInfo::meters`)
+  for (sds, sds_label) in objects("sds", instances, labels) {
+    for m in sds.get("queryDeviceLatencyMeters").and_then(|v| v.as_array()).into_iter().flatten() {
+      let label = match labels.get("device").and_then(|d| d.get(&super::utils::json_path_str(m, "deviceId"))) {
+        Some(l) if l.get("sds_id") == sds_label.get("sds_id") => l,
+        _ => continue,
       };
 
-      if let Some(value) = size_in_kb {
-        let state: Metric = Metric::new("volume_size_in_kb".to_string(), "gauge".to_string(), "volume_size_in_kb".to_string(), label.clone(), value);
-        metric_list.push(state);
+      for (field, name, desc) in [("readAvgLatencyInMicroSec", "read", "Average read"), ("writeAvgLatencyInMicroSec", "write", "Average write")] {
+        if let Some(value) = m.get(field).and_then(|v| v.as_f64()) {
+          let latency: Metric = if opts.legacy_units {
+            Metric::new("device", format!("device_latency_{name}_us"), "gauge".to_string(), format!("{desc} latency of the device in microseconds"), label.clone(), value)
+          } else {
+            Metric::new("device", format!("device_latency_{name}_seconds"), "gauge".to_string(), format!("{desc} latency of the device in seconds"), label.clone(), value / 1_000_000.0).with_unit(Some("seconds"))
+          };
+          metric_list.push(latency);
+        }
       }
     }
   }
 
-  // Devices
-  for dl in instances.get("deviceList")
-                     .and_then(|v| v.as_array())
-                     .unwrap_or_else(|| {
-                       error!("Failed to get 'deviceList' from instances");
-                       &default_val
-                     })
-                     .iter()
-  {
-    let mut parent_sds: HashMap<&'static str, String> = HashMap::new();
-    let mut parent_sto: HashMap<&'static str, String> = HashMap::new();
-    let mut parent_pdo: HashMap<&'static str, String> = HashMap::new();
-
-    for dev in dl.as_object().iter() {
-      let mut label: HashMap<&'static str, String> = HashMap::new();
-
-      let dev_name = dev.get("name").map(|s| s.to_string().replace('"', "")).expect("dev_name Not found");
-      let dev_id = dev.get("id").map(|s| s.to_string().replace('"', "")).expect("dev_id Not found");
-      let dev_path = dev.get("deviceCurrentPathName").map(|s| s.to_string().replace("/dev/", "").replace('"', "")).expect("dev_path Not found");
-      let dev_state = match dev.get("deviceState").map(|s| s.to_string().replace('"', "")) {
-        Some(s) => {
-          match s.as_str() {
-            "Normal" | "NormalTesting" => Some(0.0),
-            "DeviceInit" => Some(1.0),
-            "DeviceRecovery" => Some(2.0),
-            "InitialTest" => Some(3.0),
-            "InitialTestDone" => Some(4.0),
-            "RemovePending" => Some(5.0),
-            _ => {
-              warn!("Unknown deviceState: {:?}", s);
-              None
-            },
-          }
-        },
-        None => None,
-      };
-      let dev_state_error = match dev.get("errorState").map(|s| s.to_string().replace('"', "")) {
-        Some(s) => {
-          match s.as_str() {
-            "None" => Some(0.0),
-            "Error" => Some(1.0),
-            "Warning" => Some(2.0),
-            "Notice" => Some(3.0),
-            "Info" => Some(4.0),
-            "Acceleration" => Some(5.0),
-            "Unrecoverable" => Some(6.0),
-            _ => {
-              warn!("Unknown errorState: {:?}", s);
-              None
-            },
-          }
-        },
-        None => None,
-      };
-      let dev_state_temperature = match dev.get("temperatureState").map(|s| s.to_string().replace('"', "")) {
-        Some(s) => {
-          match s.as_str() {
-            "NeverFailed" => Some(0.0),
-            "FailedNow" => Some(1.0),
-            "FailedPast" => Some(2.0),
-            _ => {
-              warn!("Unknown temperatureState: {:?}", s);
-              None
-            },
-          }
-        },
-        None => None,
-      };
-      let dev_state_ssd_end_of_life = match dev.get("ssdEndOfLifeState").map(|s| s.to_string().replace('"', "")) {
-        Some(s) => {
-          match s.as_str() {
-            "NeverFailed" => Some(0.0),
-            "FailedNow" => Some(1.0),
-            "FailedPast" => Some(2.0),
-            _ => {
-              warn!("Unknown ssdEndOfLifeState: {:?}", s);
-              None
-            },
-          }
-        },
-        None => None,
-      };
-      let dev_state_aggregated = match dev.get("aggregatedState").map(|s| s.to_string().replace('"', "")) {
-        Some(s) => {
-          match s.as_str() {
-            "NeverFailed" => Some(0.0),
-            "FailedNow" => Some(1.0),
-            "FailedPast" => Some(2.0),
-            _ => {
-              warn!("Unknown aggregatedState: {:?}", s);
-              None
-            },
-          }
-        },
-        None => None,
-      };
+  // ReplicationConsistencyGroups: configured RPO, to be compared with the current lag statistic
+  for (rcg, label) in objects("replicationconsistencygroup", instances, labels) {
+    if let Some(value) = rcg.get("rpoInSeconds").and_then(|v| v.as_f64()) {
+      metric_list.push(Metric::new("replicationconsistencygroup", "replicationconsistencygroup_rpo_seconds".to_string(), "gauge".to_string(), "Recovery point objective of the replication consistency group".to_string(), label.clone(), value).with_unit(Some("seconds")));
+    }
+  }
 
-      label.entry("clu_name").or_insert_with(|| clu_name.to_string());
-      label.entry("clu_id").or_insert_with(|| clu_id.to_string());
-      label.entry("dev_name").or_insert_with(|| dev_name);
-      label.entry("dev_id").or_insert_with(|| dev_id.to_string());
-      label.entry("dev_path").or_insert_with(|| dev_path);
-
-      for sdsl in instances["sdsList"].as_array().unwrap().iter() {
-        for sds in sdsl.as_object().iter() {
-          if relations["parents"][&dev_id]["sds"].contains(&sds["id"].to_string().replace('"', "")) {
-            parent_sds.entry("name").or_insert_with(|| sds["name"].to_string().replace('"', ""));
-            parent_sds.entry("id").or_insert_with(|| sds["id"].to_string().replace('"', ""));
-            break;
-          }
-        }
-      }
-      for sp in instances["storagePoolList"].as_array().unwrap().iter() {
-        for sto in sp.as_object().iter() {
-          if relations["parents"][&dev_id]["storagepool"].contains(&sto["id"].to_string().replace('"', "")) {
-            parent_sto.entry("name").or_insert_with(|| sto["name"].to_string().replace('"', ""));
-            parent_sto.entry("id").or_insert_with(|| sto["id"].to_string().replace('"', ""));
-            break;
-          }
-        }
-      }
-      for pd in instances["protectionDomainList"].as_array().unwrap().iter() {
-        for pdo in pd.as_object().iter() {
-          if relations["parents"][&parent_sto["id"].to_string().replace('"', "")]["protectiondomain"].contains(&pdo["id"].to_string().replace('"', "")) {
-            parent_pdo.entry("name").or_insert_with(|| pdo["name"].to_string().replace('"', ""));
-            parent_pdo.entry("id").or_insert_with(|| pdo["id"].to_string().replace('"', ""));
-            break;
-          }
-        }
-      }
+  // FaultSets: SDS not connected to the MDM or in maintenance, a fault set is down when all its SDS are
+  for (fs, label) in objects("faultset", instances, labels) {
+    let fs_id = fs.get("id").and_then(|i| i.as_str()).unwrap_or_default();
+    let sds: Vec<&serde_json::Value> = instances.get("sdsList").and_then(|v| v.as_array()).into_iter().flatten().filter(|s| s.get("faultSetId").and_then(|i| i.as_str()) == Some(fs_id)).collect();
+    let disconnected = sds.iter().filter(|s| s.get("mdmConnectionState").and_then(|v| v.as_str()) != Some("Connected")).count();
+    let maintenance = sds.iter().filter(|s| s.get("maintenanceState").and_then(|v| v.as_str()).is_some_and(|v| v != "NoMaintenance")).count();
 
-      match parent_sds.get("name") {
-        None => {
-          error!("Failed to get 'name' from parent_sds");
-          continue;
-        },
-        Some(o) => {
-          label.entry("sds_name").or_insert_with(|| o.to_string());
-        },
-      }
-      match parent_sds.get("id") {
-        None => {
-          error!("Failed to get 'id' from parent_sds");
-          continue;
-        },
-        Some(o) => {
-          label.entry("sds_id").or_insert_with(|| o.to_string());
-        },
-      }
-      match parent_sto.get("name") {
-        None => {
-          error!("Failed to get 'name' from parent_sto");
-          continue;
-        },
-        Some(o) => {
-          label.entry("sto_name").or_insert_with(|| o.to_string());
-        },
-      }
-      match parent_sto.get("id") {
-        None => {
-          error!("Failed to get 'id' from parent_sto");
-          continue;
-        },
-        Some(o) => {
-          label.entry("sto_id").or_insert_with(|| o.to_string());
-        },
+    metric_list.push(Metric::new("faultset", "faultset_sds_disconnected".to_string(), "gauge".to_string(), "Number of SDS of the fault set disconnected from the MDM".to_string(), label.clone(), disconnected as f64));
+    metric_list.push(Metric::new("faultset", "faultset_sds_in_maintenance".to_string(), "gauge".to_string(), "Number of SDS of the fault set in maintenance".to_string(), label.clone(), maintenance as f64));
+  }
+
+  if metric_list.is_empty() {
+    None
+  } else {
+    Some(metric_list)
+  }
+}
+
+/// Labels of a volume to SDC mapping (`mappedSdcInfo` entry): the volume labels with the SDC and its access mode
+fn mapping_labels(map: &serde_json::Value, label: &HashMap<&'static str, String>, labels: &HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>) -> HashMap<&'static str, String> {
+  let sdc_id = super::utils::json_path_str(map, "sdcId");
+  let mut l = label.clone();
+  l.insert("sdc_name", labels.get("sdc").and_then(|s| s.get(&sdc_id)).and_then(|s| s.get("sdc_name")).cloned().unwrap_or_else(|| super::utils::json_path_str(map, "sdcName")));
+  l.insert("sdc_id", sdc_id);
+  l.insert("access_mode", super::utils::json_path_str(map, "accessMode"));
+  l
+}
+
+/// Utilisation of the QoS limits of the volumes mapped to a single SDC, from the volume user data IOPS and bandwidth (`userDataReadBwc` / `userDataWriteBwc`)
+fn convert_qos(instances: &Map<String, serde_json::Value>, stats: &Map<String, serde_json::Value>, labels: &HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>) -> Option<Vec<Metric>> {
+  let mut metric_list: Vec<Metric> = Vec::new();
+
+  for (vol, label) in objects("volume", instances, labels) {
+    // The statistics are per volume, not per SDC: the ratio is only exact with a single mapping
+    let map = match vol.get("mappedSdcInfo").and_then(|v| v.as_array()).map(|a| a.as_slice()) {
+      Some([map]) => map,
+      _ => continue,
+    };
+    let vol_stats = match vol.get("id").and_then(|i| i.as_str()).and_then(|id| stats.get("Volume")?.get(id)) {
+      None => continue,
+      Some(s) => s,
+    };
+    let (read, write) = match (vol_stats.get("userDataReadBwc").and_then(decode_bwc), vol_stats.get("userDataWriteBwc").and_then(decode_bwc)) {
+      (Some(r), Some(w)) => (r, w),
+      _ => continue,
+    };
+    let (iops, bw_kb) = (read.0 + write.0, read.1 + write.1);
+
+    // Unlimited mappings (0) have no utilisation
+    let l = mapping_labels(map, label, labels);
+    if let Some(limit) = map.get("limitIops").and_then(|v| v.as_f64()).filter(|v| *v > 0.0) {
+      metric_list.push(Metric::new("volume", "volume_qos_iops_utilisation".to_string(), "gauge".to_string(), "Volume IOPS / IOPS limit of the SDC mapping (volumes mapped to a single SDC)".to_string(), l.clone(), iops / limit));
+    }
+    if let Some(limit) = map.get("limitBwInMbps").and_then(|v| v.as_f64()).filter(|v| *v > 0.0) {
+      metric_list.push(Metric::new("volume", "volume_qos_bw_utilisation".to_string(), "gauge".to_string(), "Volume bandwidth / bandwidth limit of the SDC mapping (volumes mapped to a single SDC)".to_string(), l, bw_kb / (limit * 1024.0)));
+    }
+  }
+
+  if metric_list.is_empty() {
+    None
+  } else {
+    Some(metric_list)
+  }
+}
+
+/// Build the `<type>_info` metrics (value 1) from the instance fields (`info_definition.json`)
+fn convert_infos(defs: &Definitions, instances: &Map<String, serde_json::Value>, labels: &HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>) -> Option<Vec<Metric>> {
+  let mut metric_list: Vec<Metric> = Vec::new();
+
+  for (otype, infos) in defs.infos.iter() {
+    for (o, label) in objects(otype, instances, labels) {
+      let mut l = label.clone();
+      for (k, path) in infos.iter() {
+        l.entry(super::utils::intern(k)).or_insert_with(|| super::utils::json_path_str(o, path));
       }
-      match parent_pdo.get("name") {
-        None => {
-          error!("Failed to get 'name' from parent_pdo");
-          continue;
+      metric_list.push(Metric::new(otype, metric_name(otype, "info", ""), "gauge".to_string(), format!("{otype} information"), l, 1.0));
+    }
+  }
+
+  if metric_list.is_empty() {
+    None
+  } else {
+    Some(metric_list)
+  }
+}
+
+/// Numeric (or StateSet) metric of the current state of an object
+fn state_metric(d: &StateDef, state: &str, label: &HashMap<&'static str, String>, opts: &Options, metric_list: &mut Vec<Metric>) {
+  // Numeric and boolean fields (settings)
+  if d.values.is_empty() {
+    let value = match state {
+      "true" => 1.0,
+      "false" => 0.0,
+      s => match s.parse::<f64>() {
+        Ok(v) => v * d.scale.unwrap_or(1.0),
+        Err(_) => {
+          warn!("Non numeric {} ({}): {:?}", d.field, d.otype, state);
+          return;
         },
-        Some(o) => {
-          label.entry("pdo_name").or_insert_with(|| o.to_string());
+      },
+    };
+    metric_list.push(Metric::new(&d.prefix, metric_name(&d.prefix, &d.name, ""), "gauge".to_string(), d.help.clone(), label.clone(), value));
+    return;
+  }
+  if opts.state_set {
+    state_set(d, state, label, metric_list);
+    return;
+  }
+
+  let value = match d.values.iter().find(|(s, _)| *s == state) {
+    Some((_, v)) => *v,
+    None => {
+      match d.unknown {
+        Some(v) => {
+          debug!("Unknown {} ({}): {:?}", d.field, d.otype, state);
+          v
         },
-      }
-      match parent_pdo.get("id") {
         None => {
-          error!("Failed to get 'id' from parent_pdo");
-          continue;
-        },
-        Some(o) => {
-          label.entry("pdo_id").or_insert_with(|| o.to_string());
+          warn!("Unknown {} ({}): {:?}", d.field, d.otype, state);
+          return;
         },
       }
+    },
+  };
+  metric_list.push(Metric::new(&d.prefix, metric_name(&d.prefix, &d.name, ""), "gauge".to_string(), d.help.clone(), label.clone(), value));
+}
 
-      if let Some(value) = dev_state {
-        let state: Metric = Metric::new("device_state".to_string(), "gauge".to_string(), "deviceState: Normal,NormalTesting=0.0 or DeviceInit=1.0 or DeviceRecovery=2.0 or InitialTest=3.0 or InitialTestDone=4.0 or RemovePending=5.0".to_string(), label.clone(), value);
-        metric_list.push(state);
-      }
-      if let Some(value) = dev_state_error {
-        let state: Metric = Metric::new("device_state_error".to_string(), "gauge".to_string(), "errorState: None=0.0 or Error=1.0 or Warning=2.0 or Notice=3.0 or Info=4.0 or Acceleration=5.0 or Unrecoverable=6.0".to_string(), label.clone(), value);
-        metric_list.push(state);
-      }
-      if let Some(value) = dev_state_temperature {
-        let state: Metric = Metric::new("device_state_temperature".to_string(), "gauge".to_string(), "temperatureState: NeverFailed=0.0 or FailedNow=1.0 or FailedPast=2.0".to_string(), label.clone(), value);
-        metric_list.push(state);
-      }
-      if let Some(value) = dev_state_ssd_end_of_life {
-        let state: Metric = Metric::new("device_state_ssd_end_of_life".to_string(), "gauge".to_string(), "ssdEndOfLifeState: NeverFailed=0.0 or FailedNow=1.0 or FailedPast=2.0".to_string(), label.clone(), value);
-        metric_list.push(state);
-      }
-      if let Some(value) = dev_state_aggregated {
-        let state: Metric = Metric::new("device_state_aggregated".to_string(), "gauge".to_string(), "aggregatedState: NeverFailed=0.0 or FailedNow=1.0 or FailedPast=2.0".to_string(), label.clone(), value);
-        metric_list.push(state);
+/// Number of MDM cluster members per role (`mdmCluster` of the System) and the states of each member (`Mdm` entries of `state_definition.json`)
+fn mdm_members(defs: &Definitions, system: &serde_json::Value, label: &HashMap<&'static str, String>, opts: &Options, metric_list: &mut Vec<Metric>) {
+  let cluster = match system.get("mdmCluster") {
+    None => {
+      warn!("Failed to get 'mdmCluster' from the System");
+      return;
+    },
+    Some(c) => c,
+  };
+  let member_defs: Vec<&StateDef> = defs.states.iter().filter(|d| d.otype == "mdm").collect();
+
+  // The master is a single object, the other roles are lists
+  for (role, field) in [("master", "master"), ("slave", "slaves"), ("tiebreaker", "tieBreakers"), ("standby", "standbyMDMs")] {
+    let members: Vec<&serde_json::Value> = match cluster.get(field) {
+      Some(serde_json::Value::Array(a)) => a.iter().collect(),
+      Some(m) if m.is_object() => vec![m],
+      _ => Vec::new(),
+    };
+
+    let mut l = label.clone();
+    l.insert("role", role.to_string());
+    metric_list.push(Metric::new("mdm", metric_name("mdm", "cluster_members", ""), "gauge".to_string(), "Number of MDM cluster members per role".to_string(), l.clone(), members.len() as f64));
+
+    for m in members {
+      let mut ml = l.clone();
+      ml.insert("name", super::utils::json_path_str(m, "name"));
+      ml.insert("ip", super::utils::json_path_str(m, "ips[0]"));
+      for d in member_defs.iter() {
+        let s = super::utils::json_path_str(m, &d.field);
+        if !s.is_empty() {
+          state_metric(d, &s, &ml, opts, metric_list);
+        }
       }
     }
   }
+}
 
-  if metric_list.is_empty() {
-    None
-  } else {
-    Some(metric_list)
+/// Severities always exposed by the `alerts` counts, even without active alert
+const ALERT_SEVERITIES: &[&str] = &["ALERT_LOW", "ALERT_MEDIUM", "ALERT_HIGH", "ALERT_CRITICAL"];
+
+/// Name label of each object type, used to name the object affected by an alert
+const NAME_LABELS: &[(&str, &str)] = &[("system", "clu_name"), ("protectiondomain", "pdo_name"), ("faultset", "fs_name"), ("storagepool", "sto_name"), ("sds", "sds_name"), ("sdc", "sdc_name"), ("sdr", "sdr_name"), ("volume", "vol_name"), ("device", "dev_name"), ("replicationconsistencygroup", "rcg_name"), ("replicationpair", "rpa_name"), ("peermdm", "peer_name")];
+
+/// Build the `alert` (one series per active alert) and `alerts` (count per severity) metrics, with the labels of the System
+fn convert_alerts(alerts: &[serde_json::Value], labels: &HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>) -> Option<Vec<Metric>> {
+  let system = labels.get("System").and_then(|l| l.get("System"))?;
+  let mut metric_list: Vec<Metric> = Vec::new();
+  let mut counts: BTreeMap<String, usize> = ALERT_SEVERITIES.iter().map(|s| (s.to_string(), 0)).collect();
+
+  for a in alerts {
+    let severity = super::utils::json_path_str(a, "severity");
+    let otype = super::utils::json_path_str(a, "affectedObject.type");
+    let oid = match super::utils::json_path_str(a, "affectedObject.objectId") {
+      id if id.is_empty() => super::utils::json_path_str(a, "affectedObject.id"),
+      id => id,
+    };
+    let lower = otype.to_lowercase();
+    let object_name = NAME_LABELS.iter()
+                                 .find(|(t, _)| *t == lower)
+                                 .and_then(|(t, n)| if *t == "system" { system.get(n) } else { labels.get(t).and_then(|l| l.get(&oid)).and_then(|l| l.get(n)) })
+                                 .cloned()
+                                 .unwrap_or_default();
+
+    let mut l = system.clone();
+    l.insert("alert_type", super::utils::json_path_str(a, "alertType"));
+    l.insert("severity", severity.to_string());
+    l.insert("affected_object_type", otype);
+    l.insert("affected_object_id", oid);
+    l.insert("object_name", object_name);
+    metric_list.push(Metric::new("alert", "alert".to_string(), "gauge".to_string(), "Active alert".to_string(), l, 1.0));
+    *counts.entry(severity).or_default() += 1;
+  }
+
+  for (severity, count) in counts {
+    let mut l = system.clone();
+    l.insert("severity", severity);
+    metric_list.push(Metric::new("alert", "alerts".to_string(), "gauge".to_string(), "Number of active alerts per severity".to_string(), l, count as f64));
+  }
+
+  Some(metric_list)
+}
+
+/// StateSet of a state: 1 for the current state and 0 for the others, an unknown current state is added to the set
+fn state_set(d: &StateDef, state: &str, label: &HashMap<&'static str, String>, metric_list: &mut Vec<Metric>) {
+  let mut states: Vec<&str> = d.values.iter().map(|(s, _)| s.as_str()).collect();
+  if !states.contains(&state) {
+    warn!("Unknown {} ({}): {:?}", d.field, d.otype, state);
+    states.push(state);
+  }
+
+  for s in states {
+    let mut l = label.clone();
+    l.insert("state", s.to_string());
+    metric_list.push(Metric::new(&d.prefix, metric_name(&d.prefix, &d.name, ""), "gauge".to_string(), d.help.clone(), l, f64::from(u8::from(s == state))));
+  }
+}
+
+/// Objects of a type (lowercase, `system` for the System) with their labels, the objects without labels are skipped
+fn objects<'a>(otype: &str, instances: &'a Map<String, serde_json::Value>, labels: &'a HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>) -> Vec<(&'a serde_json::Value, &'a HashMap<&'static str, String>)> {
+  if otype == "system" {
+    return instances.get("System").zip(labels.get("System").and_then(|l| l.get("System"))).into_iter().collect();
   }
+
+  let type_labels = match labels.get(otype) {
+    None => return Vec::new(),
+    Some(l) => l,
+  };
+  instances.iter()
+           .filter(|(k, _)| super::filters::list_type(k) == otype)
+           .filter_map(|(_, v)| v.as_array())
+           .flatten()
+           .filter_map(|o| o.get("id").and_then(|i| i.as_str()).and_then(|id| type_labels.get(id)).map(|l| (o, l)))
+           .collect()
 }
 
 /// Build the final metric definition that should be used to create and update the metrics
-fn convert_metrics(cfg_path: Option<&str>, stats: &Map<String, serde_json::Value>, labels: &HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>) -> Option<Vec<Metric>> {
-  let path = format!("{}{}", cfg_path.unwrap(), "/metric_definition.json");
-  let mdef = super::utils::read_json(&path).unwrap_or_else(|| panic!("Failed to loading metric definition"));
-  debug!("Loaded metric defenitions: {:?}", mdef.keys().collect::<Vec<_>>());
+fn convert_metrics(defs: &Definitions, opts: &Options, stats: &Map<String, serde_json::Value>, labels: &HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>) -> Option<Vec<Metric>> {
+  debug!("Loaded metric defenitions: {:?}", defs.metrics.keys().collect::<Vec<_>>());
 
   let mut metric_list: Vec<Metric> = Vec::new();
 
@@ -482,7 +495,11 @@ fn convert_metrics(cfg_path: Option<&str>, stats: &Map<String, serde_json::Value
         let stype: &str = &instance_type.replace('"', "");
 
         for (m, v) in metrics.as_object().unwrap().iter() {
-          if mdef.contains_key(m) {
+          if let Some(mdef) = defs.metric(stype, m) {
+            let mdef = match mdef {
+              None => continue,
+              Some(d) => d,
+            };
             let m_labels = match labels.get(stype).and_then(|l| l.get(stype)) {
               None => {
                 error!("Failed to get 'labels' from {}", stype);
@@ -491,64 +508,27 @@ fn convert_metrics(cfg_path: Option<&str>, stats: &Map<String, serde_json::Value
               Some(l) => l,
             };
 
-            if m.ends_with("Bwc") && v.is_object() {
-              let m_io_name = format!("{}_{}_iops", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_io_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_io_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              let m_io_value: f64 = div_oper(v.as_object().unwrap()["numOccured"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numSeconds"].to_string().parse::<i32>().unwrap());
-              let metric_io: Metric = Metric::new(m_io_name, m_io_type, m_io_help, m_labels.clone(), m_io_value);
-              metric_list.push(metric_io);
-
-              let m_bw_name = format!("{}_{}_kb", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_bw_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_bw_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              let m_bw_value: f64 = div_oper(v.as_object().unwrap()["totalWeightInKb"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numSeconds"].to_string().parse::<i32>().unwrap());
-              let metric_bw: Metric = Metric::new(m_bw_name, m_bw_type, m_bw_help, m_labels.clone(), m_bw_value);
-              metric_list.push(metric_bw);
-
-              let m_sz_name = format!("{}_{}_iosize_kb", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_sz_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_sz_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              let m_sz_value: f64 = div_oper(v.as_object().unwrap()["totalWeightInKb"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numOccured"].to_string().parse::<i32>().unwrap());
-              let metric_sz: Metric = Metric::new(m_sz_name, m_sz_type, m_sz_help, m_labels.clone(), m_sz_value);
-              metric_list.push(metric_sz);
-
-            } else if m.ends_with("Latency") && v.is_object() {
-              let m_io_name = format!("{}_{}_iops", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_io_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_io_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              let m_io_value: f64 = div_oper(v.as_object().unwrap()["numOccured"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numSeconds"].to_string().parse::<i32>().unwrap());
-              let metric_io: Metric = Metric::new(m_io_name, m_io_type, m_io_help, m_labels.clone(), m_io_value);
-              metric_list.push(metric_io);
-
-              let m_lat_name = format!("{}_{}_us", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_lat_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_lat_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              let m_lat_value: f64 = div_oper(v.as_object().unwrap()["totalWeightInKb"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numOccured"].to_string().parse::<i32>().unwrap());
-              let metric_lat: Metric = Metric::new(m_lat_name, m_lat_type, m_lat_help, m_labels.clone(), m_lat_value);
-              metric_list.push(metric_lat);
-
-            } else {
-              let m_name = format!("{}_{}", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              if let Some(m_value) = v.as_f64() {
-                let metric: Metric = Metric::new(m_name, m_type, m_help, m_labels.clone(), m_value);
-                metric_list.push(metric);
-              }
-            }
+            decode_metric(stype, m, v, mdef, m_labels, opts, &mut metric_list);
           } else {
             error!("Metric: {} ({}) not found in (metric_definition.json)", m, stype);
             continue;
           }
         }
+
+        if let (Some(derived), Some(m_labels)) = (defs.derived.get("system"), labels.get(stype).and_then(|l| l.get(stype))) {
+          derive_metrics(stype, derived, metrics.as_object().unwrap(), m_labels, &mut metric_list);
+        }
       }
     } else if metrics.is_object() {
       for (id, v) in metrics.as_object().unwrap().iter() {
         let stype: &str = &instance_type.replace('"', "").to_lowercase();
 
         for (m, v) in v.as_object().unwrap().iter() {
-          if mdef.contains_key(m) {
+          if let Some(mdef) = defs.metric(stype, m) {
+            let mdef = match mdef {
+              None => continue,
+              Some(d) => d,
+            };
             let m_labels = match labels.get(stype).and_then(|l| l.get(id)) {
               None => {
                 warn!("Failed to get 'labels' from {} -> {}", stype, id);
@@ -557,57 +537,16 @@ fn convert_metrics(cfg_path: Option<&str>, stats: &Map<String, serde_json::Value
               Some(l) => l,
             };
 
-            if m.ends_with("Bwc") && v.is_object() {
-              let m_io_name = format!("{}_{}_iops", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_io_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_io_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              let m_io_value: f64 = div_oper(v.as_object().unwrap()["numOccured"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numSeconds"].to_string().parse::<i32>().unwrap());
-              let metric_io: Metric = Metric::new(m_io_name, m_io_type, m_io_help, m_labels.clone(), m_io_value);
-              metric_list.push(metric_io);
-
-              let m_bw_name = format!("{}_{}_kb", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_bw_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_bw_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              let m_bw_value: f64 = div_oper(v.as_object().unwrap()["totalWeightInKb"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numSeconds"].to_string().parse::<i32>().unwrap());
-              let metric_bw: Metric = Metric::new(m_bw_name, m_bw_type, m_bw_help, m_labels.clone(), m_bw_value);
-              metric_list.push(metric_bw);
-
-              let m_sz_name = format!("{}_{}_iosize_kb", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_sz_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_sz_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              let m_sz_value: f64 = div_oper(v.as_object().unwrap()["totalWeightInKb"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numOccured"].to_string().parse::<i32>().unwrap());
-              let metric_sz: Metric = Metric::new(m_sz_name, m_sz_type, m_sz_help, m_labels.clone(), m_sz_value);
-              metric_list.push(metric_sz);
-
-            } else if m.ends_with("Latency") && v.is_object() {
-              let m_io_name = format!("{}_{}_iops", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_io_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_io_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              let m_io_value: f64 = div_oper(v.as_object().unwrap()["numOccured"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numSeconds"].to_string().parse::<i32>().unwrap());
-              let metric_io: Metric = Metric::new(m_io_name, m_io_type, m_io_help, m_labels.clone(), m_io_value);
-              metric_list.push(metric_io);
-
-              let m_lat_name = format!("{}_{}_us", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_lat_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_lat_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              let m_lat_value: f64 = div_oper(v.as_object().unwrap()["totalWeightInKb"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numOccured"].to_string().parse::<i32>().unwrap());
-              let metric_lat: Metric = Metric::new(m_lat_name, m_lat_type, m_lat_help, m_labels.clone(), m_lat_value);
-              metric_list.push(metric_lat);
-
-            } else {
-              let m_name = format!("{}_{}", stype, mdef[m].as_object().unwrap()["name"]).replace('"', "").to_lowercase();
-              let m_type = mdef[m].as_object().unwrap()["type"].to_string().replace('"', "").to_lowercase();
-              let m_help = mdef[m].as_object().unwrap()["help"].to_string().replace('"', "");
-              if let Some(m_value) = v.as_f64() {
-                let metric: Metric = Metric::new(m_name, m_type, m_help, m_labels.clone(), m_value);
-                metric_list.push(metric);
-              }
-            }
+            decode_metric(stype, m, v, mdef, m_labels, opts, &mut metric_list);
           } else {
             error!("Metric: {} ({}) not found in (metric_definition.json)", m, stype);
             continue;
           }
         }
+
+        if let (Some(derived), Some(m_labels)) = (defs.derived.get(stype), labels.get(stype).and_then(|l| l.get(id))) {
+          derive_metrics(stype, derived, v.as_object().unwrap(), m_labels, &mut metric_list);
+        }
       }
     }
   }
@@ -619,11 +558,158 @@ fn convert_metrics(cfg_path: Option<&str>, stats: &Map<String, serde_json::Value
   }
 }
 
+/// Decode a single statistic value (*Bwc, *Latency or plain number) into its metrics
+fn decode_metric(stype: &str, m: &str, v: &serde_json::Value, mdef: &MetricDef, m_labels: &HashMap<&'static str, String>, opts: &Options, metric_list: &mut Vec<Metric>) {
+  let m_type = mdef.mtype.to_lowercase();
+
+  if let (true, Some((m_io_value, m_bw_value, m_sz_value))) = (m.ends_with("Bwc"), decode_bwc(v)) {
+    metric_list.push(Metric::new(stype, metric_name(stype, &mdef.name, "_iops"), m_type.clone(), mdef.help.clone(), m_labels.clone(), m_io_value));
+
+    if opts.legacy_units {
+      metric_list.push(Metric::new(stype, metric_name(stype, &mdef.name, "_kb"), m_type.clone(), mdef.help.clone(), m_labels.clone(), m_bw_value));
+      metric_list.push(Metric::new(stype, metric_name(stype, &mdef.name, "_iosize_kb"), m_type, mdef.help.clone(), m_labels.clone(), m_sz_value));
+    } else {
+      metric_list.push(Metric::new(stype, metric_name(stype, &mdef.name, "_bytes"), m_type.clone(), mdef.help.clone(), m_labels.clone(), m_bw_value * 1024.0).with_unit(Some("bytes")));
+      metric_list.push(Metric::new(stype, metric_name(stype, &mdef.name, "_iosize_bytes"), m_type, mdef.help.clone(), m_labels.clone(), m_sz_value * 1024.0).with_unit(Some("bytes")));
+    }
+  } else if m.ends_with("Latency") && v.is_object() {
+    let m_io_value: f64 = div_oper(v.as_object().unwrap()["numOccured"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numSeconds"].to_string().parse::<i32>().unwrap());
+    metric_list.push(Metric::new(stype, metric_name(stype, &mdef.name, "_iops"), m_type.clone(), mdef.help.clone(), m_labels.clone(), m_io_value));
+
+    let m_lat_value: f64 = div_oper(v.as_object().unwrap()["totalWeightInKb"].to_string().parse::<i32>().unwrap(), v.as_object().unwrap()["numOccured"].to_string().parse::<i32>().unwrap());
+    if opts.legacy_units {
+      metric_list.push(Metric::new(stype, metric_name(stype, &mdef.name, "_us"), m_type, mdef.help.clone(), m_labels.clone(), m_lat_value));
+    } else {
+      metric_list.push(Metric::new(stype, metric_name(stype, &mdef.name, "_seconds"), m_type, mdef.help.clone(), m_labels.clone(), m_lat_value / 1_000_000.0).with_unit(Some("seconds")));
+    }
+  } else if let Some(m_value) = v.as_f64() {
+    match mdef.unit.as_deref() {
+      Some(unit) if !opts.legacy_units => {
+        metric_list.push(Metric::new(stype, metric_name(stype, &unit_name(&mdef.name, unit), ""), m_type, mdef.help.clone(), m_labels.clone(), m_value * mdef.scale.unwrap_or(1.0)).with_unit(Some(unit)));
+      },
+      _ => metric_list.push(Metric::new(stype, metric_name(stype, &mdef.name, ""), m_type, mdef.help.clone(), m_labels.clone(), m_value)),
+    }
+  }
+}
+
+/// Decode a *Bwc statistic into its IOPS, bandwidth (KB/s) and IO size (KB)
+fn decode_bwc(v: &serde_json::Value) -> Option<(f64, f64, f64)> {
+  let o = v.as_object()?;
+  let field = |f: &str| o.get(f).and_then(|n| n.to_string().parse::<i32>().ok());
+  let (num, weight, seconds) = (field("numOccured")?, field("totalWeightInKb")?, field("numSeconds")?);
+  Some((div_oper(num, seconds), div_oper(weight, seconds), div_oper(weight, num)))
+}
+
+/// Evaluate the derived metrics of an object, the properties are resolved from its statistics (`totalReadBwc.numOccured`...)
+fn derive_metrics(stype: &str, derived: &[DerivedDef], props: &Map<String, serde_json::Value>, m_labels: &HashMap<&'static str, String>, metric_list: &mut Vec<Metric>) {
+  let lookup = |path: &str| {
+    let mut parts = path.split('.');
+    let first = props.get(parts.next()?)?;
+    parts.try_fold(first, |v, p| v.get(p))?.as_f64()
+  };
+
+  for d in derived {
+    match d.expr.eval(&lookup) {
+      None => debug!("Derived metric: {} ({}) skipped, missing properties in: {}", d.name, stype, d.expr),
+      Some(value) => metric_list.push(Metric::new(stype, metric_name(stype, &d.name, ""), d.mtype.to_lowercase(), d.help.clone(), m_labels.clone(), value).with_unit(d.unit.as_deref())),
+    }
+  }
+}
+
+/// Final metric name of a statistic
+pub fn metric_name(stype: &str, name: &str, suffix: &str) -> String { format!("{stype}_{name}{suffix}").to_lowercase() }
+
+/// Replace the legacy unit suffix of a metric name by the base unit
+fn unit_name(name: &str, unit: &str) -> String {
+  let lower = name.to_lowercase();
+  let base = UNIT_SUFFIXES.iter().find(|s| lower.ends_with(*s)).map(|s| &name[..name.len() - s.len()]).unwrap_or(name);
+  format!("{base}_{unit}")
+}
+
+/// All the metric names generated by a statistic (see `decode_metric`)
+pub fn output_names(stype: &str, m: &str, mdef: &MetricDef, opts: &Options) -> Vec<String> {
+  let suffixes: &[&str] = match (m.ends_with("Bwc"), m.ends_with("Latency"), opts.legacy_units) {
+    (true, _, true) => &["_iops", "_kb", "_iosize_kb"],
+    (true, _, false) => &["_iops", "_bytes", "_iosize_bytes"],
+    (_, true, true) => &["_iops", "_us"],
+    (_, true, false) => &["_iops", "_seconds"],
+    _ => &[""],
+  };
+  let name = match mdef.unit.as_deref() {
+    Some(unit) if !opts.legacy_units && suffixes == [""] => unit_name(&mdef.name, unit),
+    _ => mdef.name.clone(),
+  };
+  suffixes.iter().map(|s| metric_name(stype, &name, s)).collect()
+}
+
 /// Divide operation (to calculate IOPS, Bandwidth, IO size, latency... from the *Bwc or *Latency metrics)
 fn div_oper(value: i32, divisor: i32) -> f64 {
   if value == 0 || divisor == 0 {
     0.0_f64
   } else {
-    (value as f64 / divisor as f64) as f64
+    value as f64 / divisor as f64
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn mdef(name: &str, unit: Option<&str>, scale: Option<f64>) -> MetricDef {
+    MetricDef { name:  name.to_string(),
+                mtype: "gauge".to_string(),
+                help:  "help".to_string(),
+                unit:  unit.map(|u| u.to_string()),
+                scale, }
+  }
+
+  fn decode(m: &str, v: serde_json::Value, mdef: &MetricDef, legacy_units: bool) -> Vec<(String, f64, Option<String>)> {
+    let opts = Options { legacy_units, ..Default::default() };
+    let mut list = Vec::new();
+    decode_metric("sds", m, &v, mdef, &HashMap::new(), &opts, &mut list);
+    list.into_iter().map(|m| (m.name, m.value, m.unit)).collect()
+  }
+
+  #[test]
+  fn unit_names() {
+    assert_eq!(unit_name("capacity_in_kb", "bytes"), "capacity_bytes");
+    assert_eq!(unit_name("maxCapacityInKb", "bytes"), "maxCapacity_bytes");
+    assert_eq!(unit_name("rebuild_mb", "bytes"), "rebuild_bytes");
+    assert_eq!(unit_name("timeout_in_microsec", "seconds"), "timeout_seconds");
+    assert_eq!(unit_name("size_bytes", "bytes"), "size_bytes");
+    assert_eq!(unit_name("capacity", "bytes"), "capacity_bytes");
+  }
+
+  #[test]
+  fn decode_bwc_units() {
+    let v = json!({ "numOccured": 100, "totalWeightInKb": 800, "numSeconds": 10 });
+    assert_eq!(decode("primaryReadBwc", v.clone(), &mdef("read", None, None), false),
+               vec![("sds_read_iops".to_string(), 10.0, None), ("sds_read_bytes".to_string(), 80.0 * 1024.0, Some("bytes".to_string())), ("sds_read_iosize_bytes".to_string(), 8.0 * 1024.0, Some("bytes".to_string()))]);
+    assert_eq!(decode("primaryReadBwc", v, &mdef("read", None, None), true), vec![("sds_read_iops".to_string(), 10.0, None), ("sds_read_kb".to_string(), 80.0, None), ("sds_read_iosize_kb".to_string(), 8.0, None)]);
+  }
+
+  #[test]
+  fn decode_latency_units() {
+    let v = json!({ "numOccured": 50, "totalWeightInKb": 25000, "numSeconds": 5 });
+    assert_eq!(decode("userDataReadLatency", v.clone(), &mdef("read_latency", None, None), false), vec![("sds_read_latency_iops".to_string(), 10.0, None), ("sds_read_latency_seconds".to_string(), 0.0005, Some("seconds".to_string()))]);
+    assert_eq!(decode("userDataReadLatency", v, &mdef("read_latency", None, None), true), vec![("sds_read_latency_iops".to_string(), 10.0, None), ("sds_read_latency_us".to_string(), 500.0, None)]);
+  }
+
+  #[test]
+  fn decode_scaled_units() {
+    let d = mdef("capacity_in_use_in_kb", Some("bytes"), Some(1024.0));
+    assert_eq!(decode("capacityInUseInKb", json!(4), &d, false), vec![("sds_capacity_in_use_bytes".to_string(), 4096.0, Some("bytes".to_string()))]);
+    assert_eq!(decode("capacityInUseInKb", json!(4), &d, true), vec![("sds_capacity_in_use_in_kb".to_string(), 4.0, None)]);
+    assert_eq!(decode("numOfDevices", json!(3), &mdef("devices", None, None), false), vec![("sds_devices".to_string(), 3.0, None)]);
+  }
+
+  #[test]
+  fn output_names_match_decode() {
+    let opts = Options::default();
+    let d = mdef("capacity_in_use_in_kb", Some("bytes"), Some(1024.0));
+    assert_eq!(output_names("sds", "capacityInUseInKb", &d, &opts), vec!["sds_capacity_in_use_bytes"]);
+    assert_eq!(output_names("sds", "primaryReadBwc", &mdef("read", None, None), &opts), vec!["sds_read_iops", "sds_read_bytes", "sds_read_iosize_bytes"]);
+    assert_eq!(output_names("sds", "userDataReadLatency", &mdef("read_latency", None, None), &Options { legacy_units: true, ..Default::default() }), vec!["sds_read_latency_iops", "sds_read_latency_us"]);
   }
 }
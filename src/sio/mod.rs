@@ -1,3 +1,7 @@
 pub mod client;
+pub mod definitions;
+pub mod expr;
+pub mod filters;
 pub mod metrics;
+pub mod rules;
 pub mod utils;
@@ -0,0 +1,265 @@
+//! Label rules (`[[label_rules]]` of the configuration file): regex capture groups and lookup files applied on an existing label
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+  collections::{BTreeMap, HashMap},
+  path::Path,
+  sync::{Arc, RwLock},
+  time::SystemTime,
+};
+
+/// Lookup file content: key -> label name -> value
+#[derive(Debug, Default)]
+struct Table {
+  modified: Option<SystemTime>,
+  columns:  Vec<String>,
+  rows:     HashMap<String, BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelRule {
+  /// Label the rule is applied on (vol_name, sdc_name...)
+  pub source: String,
+  /// Each named capture group becomes a label
+  #[serde(default, deserialize_with = "super::utils::de_regex")]
+  pub regex:  Option<Regex>,
+  /// CSV (header: key,label...) or JSON (`{"key": {"label": "value"}}`) file mapping the source value to labels
+  pub lookup: Option<String>,
+  #[serde(skip)]
+  table:      Arc<RwLock<Table>>,
+}
+
+impl LabelRule {
+  /// Check the rule and load its lookup file
+  pub fn init(&self) -> Result<()> {
+    if self.regex.is_none() && self.lookup.is_none() {
+      return Err(anyhow!("The label rule on {} has neither 'regex' nor 'lookup'", self.source));
+    }
+    if let Some(f) = &self.lookup {
+      let t = read_table(f)?;
+      info!("Loaded lookup file: {} ({} entries)", f, t.rows.len());
+      *self.table.write().unwrap() = Table { modified: modified(f), ..t };
+    }
+    Ok(())
+  }
+
+  /// Label names generated by the rule
+  pub fn label_names(&self) -> Vec<String> {
+    let mut names: Vec<String> = self.regex.iter().flat_map(|r| r.capture_names().flatten().map(|n| n.to_string())).collect();
+    names.extend(self.table.read().unwrap().columns.iter().cloned());
+    names
+  }
+
+  /// Reload the lookup file if it changed, the previous content is kept on error
+  fn refresh(&self) {
+    let f = match &self.lookup {
+      None => return,
+      Some(f) => f,
+    };
+    let modified = modified(f);
+    if modified.is_none() || self.table.read().unwrap().modified == modified {
+      return;
+    }
+
+    match read_table(f) {
+      Err(e) => error!("Failed to reload the lookup file: {}", e),
+      Ok(t) => {
+        let mut table = self.table.write().unwrap();
+        if t.columns != table.columns {
+          error!("The columns of the lookup file {} changed ({:?} -> {:?}), restart to apply them", f, table.columns, t.columns);
+          return;
+        }
+        info!("Loaded lookup file: {} ({} entries)", f, t.rows.len());
+        *table = Table { modified, ..t };
+      },
+    }
+  }
+
+  /// Labels generated from the value of the source label, empty values if it does not match
+  fn labels(&self, value: &str) -> Vec<(String, String)> {
+    let mut res: Vec<(String, String)> = Vec::new();
+
+    if let Some(r) = &self.regex {
+      let caps = r.captures(value);
+      for n in r.capture_names().flatten() {
+        res.push((n.to_string(), caps.as_ref().and_then(|c| c.name(n)).map(|m| m.as_str().to_string()).unwrap_or_default()));
+      }
+    }
+    let table = self.table.read().unwrap();
+    let row = table.rows.get(value);
+    for c in table.columns.iter() {
+      res.push((c.to_string(), row.and_then(|r| r.get(c)).cloned().unwrap_or_default()));
+    }
+    res
+  }
+}
+
+/// Apply the rules on all the label maps having their source label
+pub fn apply(rules: &[LabelRule], labels: &mut HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>>) {
+  for rule in rules {
+    rule.refresh();
+
+    for l in labels.values_mut().flat_map(|l| l.values_mut()) {
+      let value = match l.get(rule.source.as_str()) {
+        None => continue,
+        Some(v) => v.to_string(),
+      };
+      for (k, v) in rule.labels(&value) {
+        l.entry(super::utils::intern(&k)).or_insert(v);
+      }
+    }
+  }
+}
+
+fn modified(file: &str) -> Option<SystemTime> { Path::new(file).metadata().and_then(|m| m.modified()).ok() }
+
+/// Read a CSV or JSON lookup file
+fn read_table(file: &str) -> Result<Table> {
+  let mut table = Table::default();
+
+  if file.ends_with(".json") {
+    for (k, v) in super::utils::read_json(file)? {
+      let row: BTreeMap<String, String> = v.as_object()
+                                           .ok_or_else(|| anyhow!("Lookup file {file}: {k} is not an object"))?
+                                           .iter()
+                                           .map(|(c, v)| (c.to_string(), v.as_str().map(|s| s.to_string()).unwrap_or_else(|| v.to_string())))
+                                           .collect();
+      for c in row.keys() {
+        if !table.columns.contains(c) {
+          table.columns.push(c.to_string());
+        }
+      }
+      table.rows.insert(k, row);
+    }
+    table.columns.sort();
+  } else {
+    let content = std::fs::read_to_string(file).map_err(|e| anyhow!("Failed to open file: {}, {:?}", file, e.kind()))?;
+    let mut lines = content.lines().map(|l| l.trim()).enumerate().filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+    let header: Vec<&str> = lines.next().ok_or_else(|| anyhow!("Lookup file {file}: missing header"))?.1.split(',').map(|c| c.trim()).collect();
+    if header.len() < 2 {
+      return Err(anyhow!("Lookup file {file}: the header needs a key and at least one label column"));
+    }
+    table.columns = header[1..].iter().map(|c| c.to_string()).collect();
+
+    for (n, l) in lines {
+      let fields: Vec<&str> = l.split(',').map(|f| f.trim()).collect();
+      if fields.len() != header.len() {
+        return Err(anyhow!("Lookup file {file}: line {} has {} fields instead of {} (commas are not allowed in the values)", n + 1, fields.len(), header.len()));
+      }
+      let row: BTreeMap<String, String> = table.columns.iter().zip(&fields[1..]).map(|(c, f)| (c.to_string(), f.to_string())).collect();
+      table.rows.insert(fields[0].to_string(), row);
+    }
+  }
+
+  Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  /// Lookup file in the temp dir, unique per test
+  fn lookup_file(name: &str, content: &str) -> String {
+    let f = std::env::temp_dir().join(format!("sio2prom-{}-{name}", std::process::id()));
+    std::fs::write(&f, content).unwrap();
+    f.to_string_lossy().to_string()
+  }
+
+  fn rule(toml: &str) -> LabelRule {
+    let r: LabelRule = toml::from_str(toml).unwrap();
+    r.init().unwrap();
+    r
+  }
+
+  /// Is the lookup file content rejected
+  fn bad_table(name: &str, content: &str) -> bool {
+    let f = lookup_file(name, content);
+    let res = read_table(&f).is_err();
+    std::fs::remove_file(f).unwrap();
+    res
+  }
+
+  #[test]
+  fn csv_table() {
+    let f = lookup_file("table.csv", "# volume owners\nvol_name, team, env\n\ndata1, storage, prod\ndata2,backup,\n");
+    let t = read_table(&f).unwrap();
+    assert_eq!(t.columns, vec!["team", "env"]);
+    assert_eq!(t.rows["data1"], BTreeMap::from([("team".to_string(), "storage".to_string()), ("env".to_string(), "prod".to_string())]));
+    assert_eq!(t.rows["data2"]["env"], "");
+
+    assert!(bad_table("key_only.csv", "vol_name\ndata1\n"));
+    assert!(bad_table("empty.csv", "# nothing\n"));
+    std::fs::remove_file(f).unwrap();
+  }
+
+  #[test]
+  fn csv_table_field_count() {
+    for (name, content) in [("missing.csv", "vol_name, team, env\ndata1, storage, prod\ndata2,backup\n"), ("extra.csv", "vol_name, team, env\ndata1, storage, prod\ndata2, backup, prod, eu\n"), ("comma.csv", "vol_name, team, env\ndata1, storage, prod\n\ndata2, \"backup, archive\", prod\n")] {
+      let f = lookup_file(name, content);
+      let e = read_table(&f).unwrap_err().to_string();
+      let line = if name == "comma.csv" { "line 4" } else { "line 3" };
+      assert!(e.contains(line), "{name}: {e}");
+      std::fs::remove_file(f).unwrap();
+    }
+  }
+
+  #[test]
+  fn json_table() {
+    let f = lookup_file("table.json", r#"{"data1": {"team": "storage", "tier": 1}, "data2": {"env": "prod"}}"#);
+    let t = read_table(&f).unwrap();
+    assert_eq!(t.columns, vec!["env", "team", "tier"]);
+    assert_eq!(t.rows["data1"]["tier"], "1");
+    assert_eq!(t.rows["data2"]["env"], "prod");
+
+    assert!(bad_table("bad.json", r#"{"data1": "storage"}"#));
+    assert!(bad_table("list.json", r#"["data1"]"#));
+    std::fs::remove_file(f).unwrap();
+  }
+
+  #[test]
+  fn regex_labels() {
+    let r = rule("source = 'vol_name'\nregex = '^(?P<app>[a-z]+)-(?P<env>prod|dev)'");
+    assert_eq!(r.label_names(), vec!["app", "env"]);
+    assert_eq!(r.labels("web-prod-01"), vec![("app".to_string(), "web".to_string()), ("env".to_string(), "prod".to_string())]);
+    assert_eq!(r.labels("nomatch"), vec![("app".to_string(), String::new()), ("env".to_string(), String::new())]);
+
+    let mut labels: HashMap<&'static str, HashMap<String, HashMap<&'static str, String>>> = HashMap::new();
+    labels.insert("volume", HashMap::from([("v1".to_string(), HashMap::from([("vol_name", "db-dev".to_string())]))]));
+    labels.insert("sdc", HashMap::from([("c1".to_string(), HashMap::from([("sdc_name", "host1".to_string())]))]));
+    apply(&[r], &mut labels);
+    assert_eq!(labels["volume"]["v1"]["app"], "db");
+    assert_eq!(labels["volume"]["v1"]["env"], "dev");
+    assert!(!labels["sdc"]["c1"].contains_key("app"));
+
+    assert!(toml::from_str::<LabelRule>("source = 'vol_name'\nregex = '('").is_err());
+    assert!(toml::from_str::<LabelRule>("source = 'vol_name'").unwrap().init().is_err());
+  }
+
+  #[test]
+  fn reload_changed_columns() {
+    let f = lookup_file("reload.csv", "vol_name,team\ndata1,storage\n");
+    let r = rule(&format!("source = 'vol_name'\nlookup = '{f}'"));
+    assert_eq!(r.labels("data1"), vec![("team".to_string(), "storage".to_string())]);
+
+    // Same columns: the new content is applied
+    std::fs::write(&f, "vol_name,team\ndata1,backup\n").unwrap();
+    touch(&f, 10);
+    r.refresh();
+    assert_eq!(r.labels("data1"), vec![("team".to_string(), "backup".to_string())]);
+
+    // Changed columns: rejected, the previous content is kept
+    std::fs::write(&f, "vol_name,owner\ndata1,bob\n").unwrap();
+    touch(&f, 20);
+    r.refresh();
+    assert_eq!(r.label_names(), vec!["team"]);
+    assert_eq!(r.labels("data1"), vec![("team".to_string(), "backup".to_string())]);
+    std::fs::remove_file(f).unwrap();
+  }
+
+  /// Move the modification time forward, the rewrites can happen within the file system time resolution
+  fn touch(file: &str, secs: u64) { std::fs::File::options().write(true).open(file).unwrap().set_modified(SystemTime::now() + Duration::from_secs(secs)).unwrap(); }
+}
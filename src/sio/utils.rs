@@ -1,20 +1,149 @@
 //! SIO Utils
 
-use serde_json::value::Map;
-use std::{fs::File, io::Read};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use serde_json::{value::Map, Value};
+use std::{
+  collections::HashSet,
+  fs::File,
+  io::Read,
+  sync::{Mutex, OnceLock},
+};
 
 extern crate serde;
 extern crate serde_json;
 
 /// Read json file using `serde_json`
-pub fn read_json(file: &str) -> Option<Map<String, serde_json::Value>> {
-  match File::open(file) {
-    Err(e) => panic!("Failed to open file: {}, {:?}", file, e.kind()),
-    Ok(mut f) => {
-      let mut content: String = String::new();
-      f.read_to_string(&mut content).expect("Error reading file");
-      let j: serde_json::Value = serde_json::from_str::<serde_json::Value>(&content).unwrap_or_else(|_| panic!("Can't deserialize json file {file}"));
-      Some(j.as_object().unwrap().clone())
+pub fn read_json(file: &str) -> Result<Map<String, serde_json::Value>> {
+  let mut f = File::open(file).map_err(|e| anyhow!("Failed to open file: {}, {:?}", file, e.kind()))?;
+  let mut content: String = String::new();
+  f.read_to_string(&mut content).map_err(|e| anyhow!("Error reading file {}: {}", file, e))?;
+
+  match serde_json::from_str::<serde_json::Value>(&content) {
+    Ok(serde_json::Value::Object(j)) => Ok(j),
+    Ok(_) => Err(anyhow!("Json file {file} is not an object")),
+    Err(e) => Err(anyhow!("Can't deserialize json file {file}: {e}")),
+  }
+}
+
+/// Static version of a label name defined at runtime (configuration), each name is only allocated once
+pub fn intern(name: &str) -> &'static str {
+  static NAMES: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+  let mut names = NAMES.get_or_init(|| Mutex::new(HashSet::new())).lock().unwrap();
+
+  match names.get(name) {
+    Some(n) => n,
+    None => {
+      let n: &'static str = Box::leak(name.to_string().into_boxed_str());
+      names.insert(n);
+      n
     },
   }
 }
+
+/// Segment of a json path: object field or array index
+#[derive(Debug, PartialEq)]
+enum Segment<'a> {
+  Field(&'a str),
+  Index(usize),
+}
+
+/// Split a json path (`ipList[0].ip`) into its segments
+fn split_path(path: &str) -> Result<Vec<Segment<'_>>> {
+  let mut res: Vec<Segment> = Vec::new();
+
+  for part in path.split('.') {
+    let (field, indexes) = part.split_at(part.find('[').unwrap_or(part.len()));
+    if field.is_empty() {
+      return Err(anyhow!("Invalid path {path}: empty field name"));
+    }
+    res.push(Segment::Field(field));
+
+    let mut rest = indexes;
+    while !rest.is_empty() {
+      let end = rest.find(']').ok_or_else(|| anyhow!("Invalid path {path}: missing ']'"))?;
+      let idx = rest[1..end].parse::<usize>().map_err(|_| anyhow!("Invalid path {path}: invalid index {}", &rest[1..end]))?;
+      res.push(Segment::Index(idx));
+      rest = &rest[end + 1..];
+      if !rest.is_empty() && !rest.starts_with('[') {
+        return Err(anyhow!("Invalid path {path}: unexpected {rest}"));
+      }
+    }
+  }
+  Ok(res)
+}
+
+/// Validate the syntax of a json path
+pub fn valid_json_path(path: &str) -> Result<()> { split_path(path).map(|_| ()) }
+
+/// Value of a json path (`ipList[0].ip`) as label value, empty if not found
+pub fn json_path_str(v: &Value, path: &str) -> String {
+  let segments = match split_path(path) {
+    Err(_) => return String::new(),
+    Ok(s) => s,
+  };
+  let found = segments.iter().try_fold(v, |v, s| {
+                                 match s {
+                                   Segment::Field(f) => v.get(f),
+                                   Segment::Index(i) => v.get(i),
+                                 }
+                               });
+
+  match found {
+    Some(Value::String(s)) => s.to_string(),
+    Some(Value::Null) | None => String::new(),
+    Some(v) => v.to_string(),
+  }
+}
+
+/// Deserialize and compile a regex from the configuration
+pub fn de_regex<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Regex>, D::Error> {
+  let s = String::deserialize(d)?;
+  Regex::new(&s).map(Some).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn split() {
+    assert_eq!(split_path("name").unwrap(), vec![Segment::Field("name")]);
+    assert_eq!(split_path("a.b.c").unwrap(), vec![Segment::Field("a"), Segment::Field("b"), Segment::Field("c")]);
+    assert_eq!(split_path("ipList[0].ip").unwrap(), vec![Segment::Field("ipList"), Segment::Index(0), Segment::Field("ip")]);
+    assert_eq!(split_path("m[1][2]").unwrap(), vec![Segment::Field("m"), Segment::Index(1), Segment::Index(2)]);
+  }
+
+  #[test]
+  fn malformed() {
+    for p in ["", ".a", "a.", "a..b", "[0]", "a[0", "a[x]", "a[-1]", "a[]", "a[0]b", "a[0].[1]"] {
+      assert!(valid_json_path(p).is_err(), "{p} should be rejected");
+    }
+  }
+
+  #[test]
+  fn path_str() {
+    let v = json!({ "name": "sds1", "id": 12, "enabled": true, "none": null, "ipList": [{ "ip": "10.0.0.1", "role": "all" }, { "ip": "10.0.1.1" }], "matrix": [[1, 2], [3, 4]], "sub": { "deep": { "key": "value" } } });
+    assert_eq!(json_path_str(&v, "name"), "sds1");
+    assert_eq!(json_path_str(&v, "id"), "12");
+    assert_eq!(json_path_str(&v, "enabled"), "true");
+    assert_eq!(json_path_str(&v, "none"), "");
+    assert_eq!(json_path_str(&v, "sub.deep.key"), "value");
+    assert_eq!(json_path_str(&v, "ipList[1].ip"), "10.0.1.1");
+    assert_eq!(json_path_str(&v, "matrix[1][0]"), "3");
+    assert_eq!(json_path_str(&v, "sub.deep"), r#"{"key":"value"}"#);
+  }
+
+  #[test]
+  fn path_str_missing() {
+    let v = json!({ "ipList": [{ "ip": "10.0.0.1" }], "sub": { "key": "value" } });
+    assert_eq!(json_path_str(&v, "ipList[1].ip"), "");
+    assert_eq!(json_path_str(&v, "ipList[0].role"), "");
+    assert_eq!(json_path_str(&v, "sub[0]"), "");
+    assert_eq!(json_path_str(&v, "sub.key.more"), "");
+    assert_eq!(json_path_str(&v, "missing.key"), "");
+    assert_eq!(json_path_str(&v, "ipList[x]"), "");
+  }
+}